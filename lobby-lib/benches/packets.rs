@@ -0,0 +1,215 @@
+//! Encoding and decoding of a chatty lobby: batches of small lobby messages.
+//!
+//! Besides the timings, prints the heap allocations made per packet, as a count of allocations is
+//! more telling than timings for a client running next to a game loop. The decoding is compared
+//! to the copying path it replaced, and the bench fails if the allocations come back.
+
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use lobby_lib::net::packet::{message_to_packet, Packet};
+use lobby_lib::net::packet_decoder::PacketDecoder;
+use lobby_lib::net::packet_encoder::PacketEncoder;
+use lobby_lib::net::packets::NewLobbyMessage;
+use lobby_lib::net::structs::UserProfile;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Packets per batch, as sent by the server in a frame
+const BATCH_SIZE: usize = 64;
+/// Size of the reads from the socket
+const READ_SIZE: usize = 1500;
+/// Allocations per packet allowed on the encoding and decoding paths, about one for the
+/// copying baseline
+const MAX_ALLOCATIONS: f64 = 0.01;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn template() -> Packet {
+    message_to_packet(&NewLobbyMessage {
+        lobby_id: "lobby-1".to_owned(),
+        profile: Some(UserProfile {
+            user_tag: "player#1234".to_owned(),
+            display_name: "Player".to_owned(),
+            avatar_url: None,
+        }),
+        content: "gg, rematch?".to_owned(),
+    })
+    .unwrap()
+}
+
+/// Same packet as the template, sharing its payload
+fn packet(template: &Packet, payload: &Bytes) -> Packet {
+    Packet {
+        flags: template.flags,
+        packet_type: template.packet_type,
+        type_id: template.type_id,
+        data: payload.clone().into(),
+    }
+}
+
+fn encode(encoder: &mut PacketEncoder, template: &Packet, payload: &Bytes) -> usize {
+    for _ in 0..BATCH_SIZE {
+        encoder.add_packet(packet(template, payload));
+    }
+    let mut size = 0;
+    while let Some(buffer) = encoder.next_buffer() {
+        size += black_box(buffer).len();
+    }
+    size
+}
+
+/// Feed the stream as socket reads would, straight into the decoder
+fn decode(decoder: &mut PacketDecoder, stream: &[u8]) -> usize {
+    let mut stream = stream;
+    let mut count = 0;
+    while !stream.is_empty() {
+        decoder
+            .read_from(|buf| {
+                let len = buf.len().min(READ_SIZE);
+                stream.read(&mut buf[..len])
+            })
+            .unwrap();
+        while let Some(packet) = decoder.next_packet() {
+            black_box(&packet);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Feed the stream as buffer processors would, one buffer per read
+fn decode_processed(decoder: &mut PacketDecoder, stream: &Bytes) -> usize {
+    let mut count = 0;
+    for start in (0..stream.len()).step_by(READ_SIZE) {
+        let end = stream.len().min(start + READ_SIZE);
+        decoder.push_buffer(stream.slice(start..end).into());
+        while let Some(packet) = decoder.next_packet() {
+            black_box(&packet);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Baseline: the path before the direct reads, each read copied into a new buffer, then into the
+/// stream, and each payload copied out of it
+fn decode_copying(decoder: &mut PacketDecoder, stream: &[u8]) -> usize {
+    let mut count = 0;
+    for chunk in stream.chunks(READ_SIZE) {
+        decoder.push_buffer(chunk.to_vec().into());
+        while let Some(packet) = decoder.next_packet() {
+            black_box(packet.data.to_vec());
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Allocations per packet once warmed up
+fn allocations<F: FnMut()>(mut f: F) -> f64 {
+    const ROUNDS: usize = 100;
+    f();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..ROUNDS {
+        f();
+    }
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    (after - before) as f64 / (ROUNDS * BATCH_SIZE) as f64
+}
+
+fn stream(template: &Packet, payload: &Bytes) -> Bytes {
+    let mut encoder = PacketEncoder::new(8 * 1024);
+    for _ in 0..BATCH_SIZE {
+        encoder.add_packet(packet(template, payload));
+    }
+    let mut stream = Vec::new();
+    while let Some(buffer) = encoder.next_buffer() {
+        stream.extend_from_slice(&buffer);
+    }
+    stream.into()
+}
+
+fn packets(c: &mut Criterion) {
+    let template = template();
+    let payload = Bytes::copy_from_slice(&template.data);
+    let stream = stream(&template, &payload);
+
+    let mut encoder = PacketEncoder::new(8 * 1024);
+    let mut decoder = PacketDecoder::new();
+    let copying = allocations(|| {
+        decode_copying(&mut decoder, &stream);
+    });
+    let counts = [
+        (
+            "encode",
+            allocations(|| {
+                encode(&mut encoder, &template, &payload);
+            }),
+        ),
+        (
+            "decode",
+            allocations(|| {
+                decode(&mut decoder, &stream);
+            }),
+        ),
+        (
+            "decode processed",
+            allocations(|| {
+                decode_processed(&mut decoder, &stream);
+            }),
+        ),
+    ];
+    println!(
+        "Allocations per packet: decode copying {:.2} (baseline)",
+        copying
+    );
+    for &(name, count) in &counts {
+        println!("Allocations per packet: {} {:.2}", name, count);
+        assert!(
+            count <= MAX_ALLOCATIONS,
+            "{} makes {:.2} allocations per packet, the copying baseline {:.2}",
+            name,
+            count,
+            copying
+        );
+    }
+
+    let mut group = c.benchmark_group("chatty_lobby");
+    group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| encode(&mut encoder, &template, &payload))
+    });
+    group.bench_function("decode", |b| b.iter(|| decode(&mut decoder, &stream)));
+    group.bench_function("decode_processed", |b| {
+        b.iter(|| decode_processed(&mut decoder, &stream))
+    });
+    group.bench_function("decode_copying", |b| {
+        b.iter(|| decode_copying(&mut decoder, &stream))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, packets);
+criterion_main!(benches);
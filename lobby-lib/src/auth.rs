@@ -0,0 +1,43 @@
+use crate::net;
+use crate::net::packet::{message_to_packet, Packet};
+use crate::net::packets::*;
+
+/// The way a client proves its identity to the lobby server.
+#[derive(Debug, Clone)]
+pub enum AuthMethod {
+    /// Classic email and password login
+    Password { email: String, password: String },
+    /// Bearer token obtained out of band (e.g. a previous session token or an OAuth access token)
+    Token { token: String },
+    /// Opaque ticket issued by a platform (e.g. Steam, console networks),
+    /// verified by the server against the given platform
+    PlatformTicket { platform: String, ticket: Vec<u8> },
+    /// OAuth device code flow. The server issues a code which the user has to confirm
+    /// on another device, see `LobbyEvent::DeviceCodeIssued`.
+    DeviceCode { client_id: String },
+}
+
+impl AuthMethod {
+    pub fn to_packet(&self) -> net::Result<Packet> {
+        match self {
+            AuthMethod::Password { email, password } => message_to_packet(&AuthenticationRequest {
+                email: email.clone(),
+                password: password.clone(),
+            }),
+            AuthMethod::Token { token } => message_to_packet(&TokenAuthenticationRequest {
+                token: token.clone(),
+            }),
+            AuthMethod::PlatformTicket { platform, ticket } => {
+                message_to_packet(&TicketAuthenticationRequest {
+                    platform: platform.clone(),
+                    ticket: ticket.clone(),
+                })
+            }
+            AuthMethod::DeviceCode { client_id } => {
+                message_to_packet(&DeviceCodeAuthenticationRequest {
+                    client_id: client_id.clone(),
+                })
+            }
+        }
+    }
+}
@@ -1,30 +1,61 @@
 #[macro_use]
+extern crate bitflags;
+#[macro_use]
 extern crate lazy_static;
-use crate::net::connection::{ConnState, Connection};
+use crate::auth::AuthMethod;
+use crate::net::connection::{ConnState, Connection, ConnectionConfig};
 use crate::net::connection_manager::ConnectionManager;
+use crate::net::endpoint::{Endpoint, EndpointOutcome, EndpointSet, DEFAULT_FAILOVER_THRESHOLD};
 use crate::net::packet::{message_to_packet, Packet};
 use crate::net::packets::*;
+use crate::net::protocol::NegotiatedProtocol;
+use crate::net::resolver::{Resolver, SystemResolver};
 use crate::net::structs::{
     Friend, FriendRequest, FriendRequestActionChoice, LobbyInviteActionChoice, LobbyMember,
     UserProfile,
 };
-use crate::net::Message;
+use crate::net::transport::tls::TlsConfig;
+use crate::net::transport::Transport;
+use crate::net::{CustomMessage, CustomTypeId, Message};
+use crate::utils::buffer_processor::{BufferProcessor, CompressionStats};
 use log::{debug, error};
-use std::collections::VecDeque;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::net::SocketAddr;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-pub const PROTOCOL_VERSION: u16 = 1;
+/// Newest protocol version supported, the server picks one in
+/// `MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION`
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Version 1 is spoken by the servers from before the negotiation, see `net::legacy`
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
 pub const APP_VERSION: u16 = 1;
+/// Largest payload accepted by `LobbyClient::send_lobby_data`
+pub const MAX_LOBBY_DATA_SIZE: usize = 4 * 1024;
 
+pub mod auth;
 pub mod net;
 pub mod utils;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub enum ErrorCode {
     InternalError,
     InvalidCredentials,
+    InvalidProfile,
+    /// Code this version doesn't know, as sent by the server
+    Unknown(String),
+}
+
+impl ErrorCode {
+    /// Error code sent by the server, `Unknown` if it isn't one of the above
+    pub fn from_code(code: String) -> Self {
+        ErrorCode::from_str(&code).unwrap_or(ErrorCode::Unknown(code))
+    }
 }
 
 impl FromStr for ErrorCode {
@@ -34,16 +65,162 @@ impl FromStr for ErrorCode {
         match input {
             "internal_error" => Ok(ErrorCode::InternalError),
             "invalid_credentials" => Ok(ErrorCode::InvalidCredentials),
+            "invalid_profile" => Ok(ErrorCode::InvalidProfile),
             _ => Err(ErrorKind::InvalidArg(format!("Unknown error code: {}", input)).into()),
         }
     }
 }
 
+/// Machine-readable reason of a `FatalError`, sent by either side before closing
+#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+#[repr(u16)]
+pub enum CloseCode {
+    ProtocolError = 1,
+    VersionMismatch = 2,
+    Kicked = 3,
+    Banned = 4,
+    ServerShutdown = 5,
+    DuplicateLogin = 6,
+}
+
+/// Why the connection to the server ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+    /// `LobbyClient::disconnect_gracefully` was called
+    Requested,
+    /// The server said goodbye
+    ServerGoodbye {
+        message: String,
+    },
+    /// The client and the server versions are not compatible
+    VersionMismatch {
+        message: String,
+    },
+    Kicked {
+        message: String,
+    },
+    Banned {
+        message: String,
+    },
+    ServerShutdown {
+        message: String,
+    },
+    /// The same account logged in from somewhere else
+    DuplicateLogin {
+        message: String,
+    },
+    /// Unexpected or malformed data was received
+    ProtocolError {
+        message: String,
+    },
+    /// The connection was closed by the server without notice, or failed
+    ConnectionLost {
+        message: String,
+    },
+    /// Close code unknown to this version of the client
+    Other {
+        code: u16,
+        message: String,
+    },
+}
+
+impl DisconnectReason {
+    pub fn from_code(code: u16, message: String) -> Self {
+        match CloseCode::from_u16(code) {
+            Some(CloseCode::ProtocolError) => DisconnectReason::ProtocolError { message },
+            Some(CloseCode::VersionMismatch) => DisconnectReason::VersionMismatch { message },
+            Some(CloseCode::Kicked) => DisconnectReason::Kicked { message },
+            Some(CloseCode::Banned) => DisconnectReason::Banned { message },
+            Some(CloseCode::ServerShutdown) => DisconnectReason::ServerShutdown { message },
+            Some(CloseCode::DuplicateLogin) => DisconnectReason::DuplicateLogin { message },
+            None => DisconnectReason::Other { code, message },
+        }
+    }
+
+    /// Code to send in a `FatalError`, `None` for reasons which aren't reported to the peer
+    pub fn code(&self) -> Option<u16> {
+        let code = match self {
+            DisconnectReason::ProtocolError { .. } => CloseCode::ProtocolError,
+            DisconnectReason::VersionMismatch { .. } => CloseCode::VersionMismatch,
+            DisconnectReason::Kicked { .. } => CloseCode::Kicked,
+            DisconnectReason::Banned { .. } => CloseCode::Banned,
+            DisconnectReason::ServerShutdown { .. } => CloseCode::ServerShutdown,
+            DisconnectReason::DuplicateLogin { .. } => CloseCode::DuplicateLogin,
+            DisconnectReason::Other { code, .. } => return Some(*code),
+            DisconnectReason::Requested
+            | DisconnectReason::ServerGoodbye { .. }
+            | DisconnectReason::ConnectionLost { .. } => return None,
+        };
+        Some(code as u16)
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            DisconnectReason::Requested => "Disconnected",
+            DisconnectReason::ServerGoodbye { message }
+            | DisconnectReason::VersionMismatch { message }
+            | DisconnectReason::Kicked { message }
+            | DisconnectReason::Banned { message }
+            | DisconnectReason::ServerShutdown { message }
+            | DisconnectReason::DuplicateLogin { message }
+            | DisconnectReason::ProtocolError { message }
+            | DisconnectReason::ConnectionLost { message }
+            | DisconnectReason::Other { message, .. } => message,
+        }
+    }
+
+    /// Whether reconnecting automatically may succeed. Reconnecting after a ban, a version
+    /// mismatch or a duplicate login would only fail again, or kick the other session.
+    pub fn should_reconnect(&self) -> bool {
+        !matches!(
+            self,
+            DisconnectReason::Requested
+                | DisconnectReason::VersionMismatch { .. }
+                | DisconnectReason::Kicked { .. }
+                | DisconnectReason::Banned { .. }
+                | DisconnectReason::DuplicateLogin { .. }
+        )
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code() {
+            Some(code) => write!(f, "{} (code {})", self.message(), code),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum LobbyEvent {
+    /// A connection attempt started (including reconnections)
+    Connecting,
+    /// Resolving or connecting to the server failed
+    ConnectFailed {
+        reason: String,
+    },
+    /// The transport was established, but the server didn't complete the handshake in time
+    HandshakeTimedOut,
     ConnectionEstablished,
+    /// The client is now connected through this endpoint
+    EndpointChanged {
+        url: String,
+        region: Option<String>,
+    },
     Disconnected {
-        message: String,
+        reason: DisconnectReason,
+    },
+    /// A packet of a type unknown to this version was received and skipped,
+    /// the server being probably newer
+    UnknownPacket {
+        type_id: u16,
+        size: usize,
+    },
+    /// Custom packet of a type without handler, see `LobbyClient::on_custom`
+    Custom {
+        type_id: u16,
+        payload: Vec<u8>,
     },
     AuthSuccess {
         session_token: String,
@@ -52,6 +229,25 @@ pub enum LobbyEvent {
     AuthFailure {
         error_code: ErrorCode,
     },
+    DeviceCodeIssued {
+        user_code: String,
+        verification_uri: String,
+        expires_in: Duration,
+    },
+    ProfileUpdated {
+        user_profile: UserProfile,
+    },
+    ProfileUpdateFailure {
+        error_code: ErrorCode,
+    },
+    ProfileFetched {
+        user_tag: String,
+        user_profile: Option<UserProfile>,
+    },
+    UserSearchResults {
+        query: String,
+        results: Vec<UserProfile>,
+    },
     FriendRequestsUpdated {
         as_invitee: Vec<FriendRequest>,
         as_inviter: Vec<FriendRequest>,
@@ -85,6 +281,13 @@ pub enum LobbyEvent {
         lobby_id: String,
         profile: Option<UserProfile>,
         content: String,
+    },
+    /// Data sent by a member of the lobby, see `LobbyClient::send_lobby_data`
+    LobbyDataReceived {
+        lobby_id: String,
+        profile: UserProfile,
+        channel: String,
+        payload: Vec<u8>,
     }, // TODO: error events
 }
 
@@ -97,17 +300,42 @@ pub enum ErrorKind {
     InvalidArg(String),
 }
 
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ClientStats {
+    /// Only present when compression was negotiated with the server
+    pub compression: Option<CompressionStats>,
+}
+
+/// Deserializes the payload of a custom packet and hands it to the application
+type CustomHandler = Box<dyn FnMut(&[u8])>;
+
 pub struct LobbyClient {
-    addr: SocketAddr,
+    /// Address of the server we are connected to, once dialing succeeded
+    addr: Option<SocketAddr>,
+    endpoints: EndpointSet,
+    /// The current connection got established
+    established: bool,
+    /// Disconnected on purpose, or for a reason making reconnecting pointless
+    stay_disconnected: bool,
+    dial_failed_at: Option<Instant>,
     reconnect_interval: Option<Duration>,
     last_reconnect_attempt: Option<Instant>,
+    auth_method: Option<AuthMethod>,
+    profile_cache: HashMap<String, UserProfile>,
     connection_manager: ConnectionManager,
     incoming_events: VecDeque<LobbyEvent>,
+    custom_handlers: HashMap<u16, CustomHandler>,
 }
 
 pub struct LobbyClientBuilder<'a> {
     url: &'a str,
     reconnect_interval: Option<Duration>,
+    auth_method: Option<AuthMethod>,
+    endpoints: Vec<Endpoint>,
+    region: Option<String>,
+    failover_threshold: u32,
+    resolver: Arc<dyn Resolver>,
+    connection_config: ConnectionConfig,
 }
 
 impl<'a> LobbyClientBuilder<'a> {
@@ -115,6 +343,12 @@ impl<'a> LobbyClientBuilder<'a> {
         Self {
             url,
             reconnect_interval: None,
+            auth_method: None,
+            endpoints: Vec::new(),
+            region: None,
+            failover_threshold: DEFAULT_FAILOVER_THRESHOLD,
+            resolver: Arc::new(SystemResolver),
+            connection_config: ConnectionConfig::default(),
         }
     }
 
@@ -123,34 +357,173 @@ impl<'a> LobbyClientBuilder<'a> {
         self
     }
 
+    /// Authenticate automatically with the given method every time
+    /// the connection is established (including reconnections).
+    pub fn with_auth_method(mut self, auth_method: AuthMethod) -> Self {
+        self.auth_method = Some(auth_method);
+        self
+    }
+
+    /// Secure the connection with TLS
+    pub fn with_tls(mut self, tls_config: TlsConfig) -> Self {
+        self.connection_config.tls = Some(tls_config);
+        self
+    }
+
+    /// Compress buffers bigger than the given size (in bytes), if the server supports it
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.connection_config.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// Add a buffer processor (e.g. encryption) to every connection.
+    /// See `Connection::add_buffer_processor` for the ordering.
+    pub fn with_buffer_processor<F>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> Box<dyn BufferProcessor> + 'static,
+    {
+        self.connection_config
+            .buffer_processors
+            .push(Rc::new(factory));
+        self
+    }
+
+    /// Add a fallback endpoint, the url given to `new` being the primary endpoint
+    pub fn with_endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.endpoints.push(endpoint);
+        self
+    }
+
+    /// Prefer the endpoints of the given region
+    pub fn with_region(mut self, region: &str) -> Self {
+        self.region = Some(region.to_owned());
+        self
+    }
+
+    /// Fail over to the next endpoint after this many consecutive connection failures
+    pub fn with_failover_threshold(mut self, failures: u32) -> Self {
+        self.failover_threshold = failures;
+        self
+    }
+
+    /// Give up connecting after the given time (default 10s)
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_config.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up waiting for the server handshake after the given time,
+    /// once the transport is established (default 10s)
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_config.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Close anyway if the server didn't acknowledge a graceful disconnection after the given
+    /// time (default 2s)
+    pub fn with_goodbye_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_config.goodbye_timeout = timeout;
+        self
+    }
+
+    /// Resolve the server host name with the given resolver instead of the system one
+    pub fn with_resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Open connections with the given connector, e.g. to use a custom transport
+    pub fn with_connector<F>(mut self, connector: F) -> Self
+    where
+        F: Fn(SocketAddr) -> std::io::Result<Box<dyn Transport>> + 'static,
+    {
+        self.connection_config.connector = Some(Rc::new(connector));
+        self
+    }
+
+    /// See `Endpoint` for the url format
     pub fn build(&self) -> Result<LobbyClient> {
-        let addr = self
-            .url
-            .parse()
-            .map_err(|_| ErrorKind::InvalidArg(format!("Invalid url {}", self.url)))?;
+        let mut endpoints = Vec::with_capacity(self.endpoints.len() + 1);
+        for endpoint in std::iter::once(Endpoint::new(self.url)).chain(self.endpoints.clone()) {
+            let dialer = endpoint
+                .dialer(self.resolver.clone(), &self.connection_config)
+                .ok_or_else(|| ErrorKind::InvalidArg(format!("Invalid url {}", endpoint.url())))?;
+            endpoints.push((endpoint, dialer));
+        }
         Ok(LobbyClient {
-            addr,
+            addr: None,
+            endpoints: EndpointSet::new(endpoints, self.region.clone(), self.failover_threshold),
+            established: false,
+            stay_disconnected: false,
+            dial_failed_at: None,
             reconnect_interval: self.reconnect_interval,
             last_reconnect_attempt: None,
-            connection_manager: ConnectionManager::new(),
+            auth_method: self.auth_method.clone(),
+            profile_cache: HashMap::new(),
+            connection_manager: ConnectionManager::new(self.connection_config.clone()),
             incoming_events: VecDeque::new(),
+            custom_handlers: HashMap::new(),
         })
     }
 }
 
 impl LobbyClient {
+    /// Resolve the server host and connect to it, closing the current connection if any
     pub fn connect(&mut self) {
-        self.connection_manager.connect(self.addr);
+        if let Some(addr) = self.addr.take() {
+            self.connection_manager.disconnect(addr, true);
+        }
+        self.established = false;
+        self.stay_disconnected = false;
+        self.dial_failed_at = None;
+        self.incoming_events.push_back(LobbyEvent::Connecting);
+        self.endpoints.connect(&mut self.connection_manager);
     }
 
     pub fn disconnect(&mut self, free: bool) {
-        self.connection_manager.disconnect(self.addr, free);
+        self.endpoints.cancel(&mut self.connection_manager);
+        if let Some(addr) = self.addr {
+            self.connection_manager.disconnect(addr, free);
+            if free {
+                self.addr = None;
+            }
+        }
+    }
+
+    /// Send the queued packets, then say goodbye to the server and close once it acknowledged,
+    /// see `LobbyClientBuilder::with_goodbye_timeout`. No reconnection is attempted afterwards.
+    pub fn disconnect_gracefully(&mut self, reason: &str) {
+        self.stay_disconnected = true;
+        self.endpoints.cancel(&mut self.connection_manager);
+        if let Some(addr) = self.addr {
+            self.connection_manager.disconnect_gracefully(addr, reason);
+        }
     }
 
     pub fn tick(&mut self, timeout: Duration) {
         self.try_to_reconnect();
+        self.poll_endpoints(self.incoming_events.len());
+        let queued = self.incoming_events.len();
         self.connection_manager
             .tick(&mut self.incoming_events, timeout);
+        // Pick the connection which just got established before its events are handled
+        self.poll_endpoints(queued);
+    }
+
+    /// Protocol version and features picked by the server, once the connection is established
+    pub fn negotiated_protocol(&self) -> Option<NegotiatedProtocol> {
+        self.connection().and_then(|conn| conn.protocol)
+    }
+
+    pub fn stats(&mut self) -> ClientStats {
+        let compression = match self
+            .connection_mut()
+            .and_then(|conn| conn.compression.as_ref())
+        {
+            Some(handle) if handle.is_enabled() => Some(handle.stats()),
+            _ => None,
+        };
+        ClientStats { compression }
     }
 
     pub fn poll_events(&mut self, events: &mut Vec<LobbyEvent>) {
@@ -160,6 +533,9 @@ impl LobbyClient {
                 break;
             }
             if let Some(event) = self.incoming_events.pop_front() {
+                if self.route_custom(&event) {
+                    continue;
+                }
                 self.handle_event(&event);
                 events.push(event);
             }
@@ -167,6 +543,10 @@ impl LobbyClient {
     }
 
     pub fn authenticate(&mut self, email: String, password: String) {
+        self.authenticate_with(&AuthMethod::Password { email, password });
+    }
+
+    pub fn authenticate_with(&mut self, auth_method: &AuthMethod) {
         if !self.initialized() {
             error!("authenticate() called before initialized");
             return;
@@ -175,7 +555,40 @@ impl LobbyClient {
             error!("authenticate() called when closed");
             return;
         }
-        self.send_to_lobby(AuthenticationRequest { email, password });
+        match (auth_method.to_packet(), self.addr) {
+            (Ok(packet), Some(addr)) => self.send_packet(addr, packet),
+            (Ok(_), None) => error!("authenticate() called when not connected"),
+            (Err(err), _) => error!("Could not create authentication packet: {:?}", err),
+        }
+    }
+
+    pub fn update_profile(&mut self, display_name: Option<String>, avatar_url: Option<String>) {
+        self.send_to_lobby(UpdateProfile {
+            display_name,
+            avatar_url,
+        });
+    }
+
+    /// Fetch the profile of the given user. Profiles are cached, so if it is already known
+    /// the `ProfileFetched` event is emitted right away without querying the server.
+    pub fn fetch_profile(&mut self, user_tag: String) {
+        if let Some(profile) = self.profile_cache.get(&user_tag) {
+            let user_profile = Some(profile.clone());
+            self.incoming_events.push_back(LobbyEvent::ProfileFetched {
+                user_tag,
+                user_profile,
+            });
+            return;
+        }
+        self.send_to_lobby(FetchProfile { user_tag });
+    }
+
+    pub fn cached_profile(&self, user_tag: &str) -> Option<&UserProfile> {
+        self.profile_cache.get(user_tag)
+    }
+
+    pub fn search_users(&mut self, query: String) {
+        self.send_to_lobby(SearchUsers { query });
     }
 
     pub fn add_friend(&mut self, user_tag: String) {
@@ -214,31 +627,181 @@ impl LobbyClient {
         self.send_to_lobby(SendLobbyMessage { content })
     }
 
+    /// Relay game data to the other members of the lobby, or only to `target` if set.
+    /// The channel tells the receivers what the payload is about,
+    /// which must not be larger than `MAX_LOBBY_DATA_SIZE`.
+    pub fn send_lobby_data(
+        &mut self,
+        channel: String,
+        payload: Vec<u8>,
+        target: Option<String>,
+    ) -> Result<()> {
+        if payload.len() > MAX_LOBBY_DATA_SIZE {
+            return Err(ErrorKind::InvalidArg(format!(
+                "Lobby data payload of {} bytes is larger than {} bytes",
+                payload.len(),
+                MAX_LOBBY_DATA_SIZE
+            ))
+            .into());
+        }
+        self.send_to_lobby(SendLobbyData {
+            channel,
+            payload,
+            target,
+        });
+        Ok(())
+    }
+
+    /// Route the custom packets of type `T` to `handler`, instead of `LobbyEvent::Custom`.
+    /// Packets which can't be deserialized are dropped.
+    pub fn on_custom<T, F>(&mut self, mut handler: F)
+    where
+        T: CustomMessage,
+        F: FnMut(T) + 'static,
+    {
+        let () = CustomTypeId::<T>::VALID;
+        let handler = move |payload: &[u8]| match T::encoding().deserialize(payload) {
+            Ok(message) => handler(message),
+            Err(err) => error!("Dropping custom packet {}: {:?}", T::TYPE_ID, err),
+        };
+        self.custom_handlers.insert(T::TYPE_ID, Box::new(handler));
+    }
+
+    pub fn send_custom<T: CustomMessage>(&mut self, message: &T) {
+        let () = CustomTypeId::<T>::VALID;
+        let addr = match self.addr {
+            Some(addr) => addr,
+            None => {
+                error!("Not connected, dropping custom packet {}", T::TYPE_ID);
+                return;
+            }
+        };
+        match T::encoding().serialize(message) {
+            Ok(data) => self.send_packet(addr, Packet::custom(T::TYPE_ID, data)),
+            Err(err) => error!(
+                "Could not serialize custom packet {}: {:?}",
+                T::TYPE_ID,
+                err
+            ),
+        }
+    }
+
+    /// Whether the event is a custom packet which was handed to its handler
+    fn route_custom(&mut self, event: &LobbyEvent) -> bool {
+        if let LobbyEvent::Custom { type_id, payload } = event {
+            if let Some(handler) = self.custom_handlers.get_mut(type_id) {
+                handler(payload);
+                return true;
+            }
+        }
+        false
+    }
+
     fn handle_event(&mut self, event: &LobbyEvent) {
         match event {
             LobbyEvent::ConnectionEstablished => {
                 self.last_reconnect_attempt = None;
+                self.established = true;
+                self.endpoints.established();
+                if let Some(auth_method) = self.auth_method.clone() {
+                    self.authenticate_with(&auth_method);
+                }
+            }
+            LobbyEvent::Disconnected { reason } => {
+                // We may miss profile updates while disconnected
+                self.profile_cache.clear();
+                if !reason.should_reconnect() {
+                    debug!("Not reconnecting: {}", reason);
+                    self.stay_disconnected = true;
+                }
+            }
+            LobbyEvent::AuthSuccess { user_profile, .. }
+            | LobbyEvent::ProfileUpdated { user_profile }
+            | LobbyEvent::ProfileFetched {
+                user_profile: Some(user_profile),
+                ..
+            } => {
+                self.cache_profile(user_profile);
+            }
+            LobbyEvent::UserSearchResults { results, .. } => {
+                for user_profile in results {
+                    self.cache_profile(user_profile);
+                }
+            }
+            LobbyEvent::FriendListUpdated { friend_list } => {
+                for friend in friend_list {
+                    self.cache_profile(&friend.user_profile);
+                }
+            }
+            LobbyEvent::LobbyMemberUpdate { members, .. } => {
+                for member in members {
+                    self.cache_profile(&member.user_profile);
+                }
             }
             _ => {}
         }
     }
 
+    fn cache_profile(&mut self, user_profile: &UserProfile) {
+        self.profile_cache
+            .insert(user_profile.user_tag.clone(), user_profile.clone());
+    }
+
     fn send_to_lobby<'de, T: Message<'de>>(&mut self, message: T) {
-        self.send_message(self.addr, &message);
+        match self.addr {
+            Some(addr) => self.send_message(addr, &message),
+            None => error!("Not connected, dropping {:?}", message.packet_type()),
+        }
     }
 
     fn initialized(&mut self) -> bool {
-        self.connection_mut().state >= ConnState::Authenticating
+        self.connection_mut().is_some_and(|conn| {
+            matches!(conn.state, ConnState::Authenticating | ConnState::Running)
+        })
     }
 
     fn closed(&mut self) -> bool {
-        self.connection_mut().state == ConnState::Closed
+        match self.connection_mut() {
+            Some(conn) => conn.state == ConnState::Closed,
+            None => true,
+        }
     }
 
-    fn connection_mut(&mut self) -> &mut Connection {
-        self.connection_manager
-            .connect_mut(self.addr)
-            .expect("connect() never called")
+    fn connection(&self) -> Option<&Connection> {
+        let addr = self.addr?;
+        self.connection_manager.connection(addr)
+    }
+
+    fn connection_mut(&mut self) -> Option<&mut Connection> {
+        let addr = self.addr?;
+        self.connection_manager.connect_mut(addr)
+    }
+
+    /// Outcome events are inserted at the given position of the queue,
+    /// so they come before the events of the connection received in the meantime
+    fn poll_endpoints(&mut self, position: usize) {
+        match self.endpoints.poll(&mut self.connection_manager) {
+            Some(EndpointOutcome::Connected {
+                addr,
+                endpoint_changed,
+            }) => {
+                self.addr = Some(addr);
+                if let Some(endpoint) = endpoint_changed {
+                    let event = LobbyEvent::EndpointChanged {
+                        url: endpoint.url().to_owned(),
+                        region: endpoint.region().map(str::to_owned),
+                    };
+                    self.incoming_events.insert(position, event);
+                }
+            }
+            Some(EndpointOutcome::Failed { message }) => {
+                error!("{}", message);
+                self.dial_failed_at = Some(Instant::now());
+                self.incoming_events
+                    .insert(position, LobbyEvent::ConnectFailed { reason: message });
+            }
+            None => {}
+        }
     }
 
     fn send_message<'de, T: Message<'de>>(&mut self, peer: SocketAddr, message: &T) {
@@ -259,25 +822,725 @@ impl LobbyClient {
     }
 
     fn try_to_reconnect(&mut self) {
-        if !self.closed() {
+        if self.stay_disconnected || self.endpoints.is_dialing() || !self.closed() {
             return;
         }
 
         if let Some(interval) = self.reconnect_interval {
-            let closed_at = self.connection_mut().closed_time;
-            match (self.last_reconnect_attempt, closed_at) {
-                (Some(attempt), _) if Instant::now() > attempt + interval => {
-                    debug!("Reconnecting");
-                    self.last_reconnect_attempt = Some(Instant::now());
-                    self.connect();
+            let closed_at = match self.connection_mut() {
+                Some(conn) => conn.closed_time,
+                None => self.dial_failed_at,
+            };
+            let due = match (self.last_reconnect_attempt, closed_at) {
+                (Some(attempt), _) => Instant::now() > attempt + interval,
+                (None, Some(closed_at)) => Instant::now() > closed_at + interval,
+                _ => false,
+            };
+            if due {
+                // Dialing failures are already accounted for
+                if self.addr.is_some() && !self.established {
+                    if let Some(current) = self.endpoints.current() {
+                        self.endpoints.failed(current);
+                    }
                 }
-                (None, Some(closed_at)) if Instant::now() > closed_at + interval => {
-                    debug!("Reconnecting");
-                    self.last_reconnect_attempt = Some(Instant::now());
-                    self.connect();
+                debug!("Reconnecting");
+                self.last_reconnect_attempt = Some(Instant::now());
+                self.connect();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::AuthMethod;
+    use crate::net::endpoint::Endpoint;
+    use crate::net::mock_server::{self_signed_tls_config, tick_until, MockServer};
+    use crate::net::packet::{message_to_packet, packet_to_message, Encoding, Packet};
+    use crate::net::packets::*;
+    use crate::net::protocol::Capabilities;
+    use crate::net::structs::UserProfile;
+    use crate::net::transport::tls::TlsConfig;
+    use crate::net::{CustomMessage, ErrorKind};
+    use crate::utils::buffer_processor::{BufferProcessor, Direction};
+    use crate::utils::byte_buffer::ByteBuffer;
+    use crate::{CloseCode, DisconnectReason, LobbyClientBuilder, LobbyEvent};
+    use ring::digest::{digest, SHA256};
+    use serde::{Deserialize, Serialize};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::net::{SocketAddr, TcpListener};
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn auth_success() -> AuthenticationResponse {
+        AuthenticationResponse {
+            error_code: None,
+            session_token: Some("session".to_owned()),
+            user_profile: Some(UserProfile {
+                user_tag: "user#1234".to_owned(),
+                display_name: "User".to_owned(),
+                avatar_url: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn default_auth_method() {
+        let server = MockServer::start(|packet| match packet.packet_type {
+            PacketType::TokenAuthenticationRequest => {
+                vec![message_to_packet(&auth_success()).unwrap()]
+            }
+            _ => vec![],
+        });
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .with_auth_method(AuthMethod::Token {
+                token: "token".to_owned(),
+            })
+            .build()
+            .unwrap();
+        client.connect();
+
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::AuthSuccess { .. })
+        });
+
+        let received = server.received();
+        let request = received
+            .iter()
+            .find(|packet| packet.packet_type == PacketType::TokenAuthenticationRequest)
+            .unwrap();
+        let request = packet_to_message::<TokenAuthenticationRequest>(request).unwrap();
+        assert_eq!(request.token, "token");
+    }
+
+    #[test]
+    fn device_code_auth() {
+        let server = MockServer::start(|packet| match packet.packet_type {
+            PacketType::DeviceCodeAuthenticationRequest => vec![
+                message_to_packet(&DeviceCodeIssued {
+                    user_code: "ABCD-EFGH".to_owned(),
+                    verification_uri: "https://example.com/device".to_owned(),
+                    expires_in: 600,
+                })
+                .unwrap(),
+                message_to_packet(&auth_success()).unwrap(),
+            ],
+            _ => vec![],
+        });
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .build()
+            .unwrap();
+        client.connect();
+
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+        client.authenticate_with(&AuthMethod::DeviceCode {
+            client_id: "game".to_owned(),
+        });
+
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::AuthSuccess { .. })
+        });
+        match &events[..] {
+            [.., LobbyEvent::DeviceCodeIssued { user_code, .. }, LobbyEvent::AuthSuccess { .. }] => {
+                assert_eq!(user_code, "ABCD-EFGH")
+            }
+            _ => panic!("Device code not issued before auth success: {:?}", events),
+        }
+    }
+
+    #[test]
+    fn profile_cache() {
+        let server = MockServer::start(|packet| match packet.packet_type {
+            PacketType::FetchProfile => {
+                let msg = packet_to_message::<FetchProfile>(packet).unwrap();
+                vec![message_to_packet(&FetchProfileResponse {
+                    user_tag: msg.user_tag.clone(),
+                    user_profile: Some(UserProfile {
+                        user_tag: msg.user_tag,
+                        display_name: "Friend".to_owned(),
+                        avatar_url: None,
+                    }),
+                })
+                .unwrap()]
+            }
+            _ => vec![],
+        });
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .build()
+            .unwrap();
+        client.connect();
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+
+        for _ in 0..2 {
+            client.fetch_profile("friend#1234".to_owned());
+            tick_until(&mut client, |event| {
+                matches!(event, LobbyEvent::ProfileFetched { .. })
+            });
+        }
+        assert_eq!(
+            client.cached_profile("friend#1234").unwrap().display_name,
+            "Friend"
+        );
+        let fetch_count = server
+            .received()
+            .iter()
+            .filter(|packet| packet.packet_type == PacketType::FetchProfile)
+            .count();
+        assert_eq!(fetch_count, 1);
+    }
+
+    #[test]
+    fn tls_custom_root() {
+        let (server_config, cert) = self_signed_tls_config();
+        let server = MockServer::start_tls(server_config, |_| vec![]);
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .with_tls(
+                TlsConfig::new()
+                    .without_default_roots()
+                    .with_root_certificate(cert),
+            )
+            .build()
+            .unwrap();
+        client.connect();
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+
+        client.search_users("user".to_owned());
+        client.tick(Duration::from_millis(10));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline
+            && !server
+                .received()
+                .iter()
+                .any(|packet| packet.packet_type == PacketType::SearchUsers)
+        {
+            client.tick(Duration::from_millis(10));
+        }
+        assert!(Instant::now() < deadline, "Server never received packet");
+    }
+
+    #[test]
+    fn websocket_transport() {
+        let server = MockServer::start_websocket(|packet| {
+            if packet.packet_type != PacketType::SearchUsers {
+                return vec![];
+            }
+            let request: SearchUsers = packet_to_message(packet).unwrap();
+            vec![message_to_packet(&SearchUsersResponse {
+                query: request.query,
+                results: vec![],
+            })
+            .unwrap()]
+        });
+        let mut client = LobbyClientBuilder::new(&format!("ws://{}/lobby", server.addr))
+            .build()
+            .unwrap();
+        client.connect();
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+
+        client.search_users("user".to_owned());
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::UserSearchResults { .. })
+        });
+        match events.last() {
+            Some(LobbyEvent::UserSearchResults { query, results }) => {
+                assert_eq!(query, "user");
+                assert!(results.is_empty());
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn parse_urls() {
+        for url in &[
+            "127.0.0.1:9000",
+            "[::1]:9000",
+            "lobby.example.com:9000",
+            "ws://localhost/lobby",
+            "ws://[::1]/lobby",
+            "wss://127.0.0.1:443/lobby",
+        ] {
+            assert!(LobbyClientBuilder::new(url).build().is_ok(), "{}", url);
+        }
+        for url in &[
+            "lobby.example.com",
+            "::1:9000",
+            "bad host:9000",
+            "lobby.example.com:port",
+            "ws:///lobby",
+        ] {
+            assert!(LobbyClientBuilder::new(url).build().is_err(), "{}", url);
+        }
+    }
+
+    /// Address nothing listens on anymore
+    fn refused_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn endpoint_changes(events: &[LobbyEvent]) -> Vec<(String, Option<String>)> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                LobbyEvent::EndpointChanged { url, region } => Some((url.clone(), region.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn connect_failures(events: &[LobbyEvent]) -> usize {
+        events
+            .iter()
+            .filter(|event| matches!(event, LobbyEvent::ConnectFailed { .. }))
+            .count()
+    }
+
+    #[test]
+    fn region_selection() {
+        let server = MockServer::start(|_| vec![]);
+        let url = server.addr.to_string();
+        let mut client = LobbyClientBuilder::new(&refused_addr().to_string())
+            .with_endpoint(Endpoint::new(&url).with_region("eu").with_priority(5))
+            .with_region("eu")
+            .build()
+            .unwrap();
+        client.connect();
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+        assert_eq!(
+            endpoint_changes(&events),
+            vec![(url, Some("eu".to_owned()))]
+        );
+        assert_eq!(connect_failures(&events), 0);
+    }
+
+    #[test]
+    fn probe_preferred_endpoints() {
+        let server = MockServer::start(|_| vec![]);
+        let url = server.addr.to_string();
+        let mut client = LobbyClientBuilder::new(&refused_addr().to_string())
+            .with_endpoint(Endpoint::new(&url))
+            .with_endpoint(Endpoint::new(&refused_addr().to_string()).with_priority(1))
+            .build()
+            .unwrap();
+        client.connect();
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+        assert_eq!(endpoint_changes(&events), vec![(url, None)]);
+        assert_eq!(connect_failures(&events), 0);
+    }
+
+    #[test]
+    fn failover() {
+        let server = MockServer::start(|_| vec![]);
+        let url = server.addr.to_string();
+        let mut client = LobbyClientBuilder::new(&refused_addr().to_string())
+            .with_endpoint(Endpoint::new(&url).with_priority(1))
+            .with_failover_threshold(2)
+            .with_reconnect_interval(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        client.connect();
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+        assert_eq!(endpoint_changes(&events), vec![(url, None)]);
+        assert_eq!(connect_failures(&events), 2);
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        // Accepts the connection, but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = LobbyClientBuilder::new(&addr.to_string())
+            .with_handshake_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        client.connect();
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::HandshakeTimedOut)
+        });
+        assert!(matches!(events.first(), Some(LobbyEvent::Connecting)));
+        assert!(client.closed());
+        drop(listener);
+    }
+
+    #[test]
+    fn resolve_hostname() {
+        let server = MockServer::start(|_| vec![]);
+        // The next address must be tried
+        let addrs = vec![refused_addr(), server.addr];
+        let resolutions = Arc::new(AtomicUsize::new(0));
+        let counter = resolutions.clone();
+        let url = format!("lobby.test:{}", server.addr.port());
+        let mut client = LobbyClientBuilder::new(&url)
+            .with_resolver(move |host: &str, _| {
+                assert_eq!(host, "lobby.test");
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(addrs.clone())
+            })
+            .build()
+            .unwrap();
+        client.connect();
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+        assert_eq!(client.addr, Some(server.addr));
+
+        // Every connection attempt resolves the host again
+        client.connect();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while resolutions.load(Ordering::SeqCst) < 2 && Instant::now() < deadline {
+            client.tick(Duration::from_millis(10));
+        }
+        assert_eq!(resolutions.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn resolution_failure() {
+        let mut client = LobbyClientBuilder::new("lobby.test:9000")
+            .with_resolver(|_: &str, _| Err(io::ErrorKind::NotFound.into()))
+            .build()
+            .unwrap();
+        client.connect();
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectFailed { .. })
+        });
+        match events.last() {
+            Some(LobbyEvent::ConnectFailed { reason }) => {
+                assert!(reason.starts_with("Could not resolve lobby.test"))
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn tls_pinned_certificate() {
+        let (server_config, cert) = self_signed_tls_config();
+        let server = MockServer::start_tls(server_config, |_| vec![]);
+        let mut pin = [0; 32];
+        pin.copy_from_slice(digest(&SHA256, &cert).as_ref());
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .with_tls(
+                TlsConfig::new()
+                    .without_default_roots()
+                    .with_pinned_certificate(pin),
+            )
+            .build()
+            .unwrap();
+        client.connect();
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+    }
+
+    #[test]
+    fn tls_pin_mismatch() {
+        let (server_config, _) = self_signed_tls_config();
+        let server = MockServer::start_tls(server_config, |_| vec![]);
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .with_tls(
+                TlsConfig::new()
+                    .without_default_roots()
+                    .with_pinned_certificate([0; 32]),
+            )
+            .build()
+            .unwrap();
+        client.connect();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !client.closed() && Instant::now() < deadline {
+            client.tick(Duration::from_millis(10));
+        }
+        assert!(client.closed());
+    }
+
+    struct CorruptedInput;
+
+    impl BufferProcessor for CorruptedInput {
+        fn process(
+            &mut self,
+            buffer: ByteBuffer,
+            output: &mut VecDeque<ByteBuffer>,
+            direction: Direction,
+        ) -> crate::net::Result<()> {
+            match direction {
+                Direction::In => Err(ErrorKind::Processing("corrupted".to_owned()).into()),
+                Direction::Out => {
+                    output.push_back(buffer);
+                    Ok(())
                 }
-                _ => {}
             }
         }
     }
+
+    #[test]
+    fn buffer_processor_error_closes_connection() {
+        let server = MockServer::start(|_| vec![]);
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .with_buffer_processor(|| Box::new(CorruptedInput))
+            .build()
+            .unwrap();
+        client.connect();
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::Disconnected { .. })
+        });
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, LobbyEvent::ConnectionEstablished)));
+        assert!(client.closed());
+    }
+
+    #[test]
+    fn graceful_disconnect() {
+        let server = MockServer::start(|packet| match packet.packet_type {
+            PacketType::Goodbye => vec![message_to_packet(&GoodbyeAck {}).unwrap()],
+            _ => vec![],
+        });
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .with_reconnect_interval(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        client.connect();
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+
+        client.search_users("user".to_owned());
+        client.disconnect_gracefully("Quit");
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::Disconnected { .. })
+        });
+        assert!(matches!(
+            events.last(),
+            Some(LobbyEvent::Disconnected {
+                reason: DisconnectReason::Requested
+            })
+        ));
+        assert!(client.closed());
+
+        let received: Vec<PacketType> = server
+            .received()
+            .iter()
+            .map(|packet| packet.packet_type)
+            .collect();
+        assert_eq!(
+            received[received.len() - 2..],
+            [PacketType::SearchUsers, PacketType::Goodbye]
+        );
+
+        // No reconnection
+        let mut events = Vec::with_capacity(16);
+        for _ in 0..5 {
+            client.tick(Duration::from_millis(10));
+            client.poll_events(&mut events);
+        }
+        assert!(events.is_empty(), "{:?}", events);
+        assert!(client.closed());
+    }
+
+    #[test]
+    fn close_codes() {
+        let reason = DisconnectReason::from_code(CloseCode::Kicked as u16, "AFK".to_owned());
+        assert_eq!(
+            reason,
+            DisconnectReason::Kicked {
+                message: "AFK".to_owned()
+            }
+        );
+        assert_eq!(reason.code(), Some(CloseCode::Kicked as u16));
+        assert!(!reason.should_reconnect());
+
+        let reason = DisconnectReason::from_code(1000, "From the future".to_owned());
+        assert_eq!(reason.code(), Some(1000));
+        assert!(reason.should_reconnect());
+        assert_eq!(DisconnectReason::Requested.code(), None);
+    }
+
+    #[test]
+    fn no_reconnect_when_banned() {
+        let server = MockServer::start(|packet| match packet.packet_type {
+            PacketType::PacketInit => vec![message_to_packet(&FatalError {
+                code: CloseCode::Banned as u16,
+                message: "Cheating".to_owned(),
+            })
+            .unwrap()],
+            _ => vec![],
+        });
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .with_reconnect_interval(Duration::from_millis(10))
+            .build()
+            .unwrap();
+        client.connect();
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::Disconnected { .. })
+        });
+        match events.last() {
+            Some(LobbyEvent::Disconnected {
+                reason: DisconnectReason::Banned { message },
+            }) => assert_eq!(message, "Cheating"),
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        let mut events = Vec::with_capacity(16);
+        for _ in 0..5 {
+            client.tick(Duration::from_millis(10));
+            client.poll_events(&mut events);
+        }
+        assert!(events.is_empty(), "{:?}", events);
+    }
+
+    #[test]
+    fn negotiated_protocol() {
+        let server = MockServer::start(|_| vec![]);
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .with_compression(512)
+            .build()
+            .unwrap();
+        assert_eq!(client.negotiated_protocol(), None);
+        client.connect();
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+        let protocol = client.negotiated_protocol().unwrap();
+        assert_eq!(protocol.version, crate::PROTOCOL_VERSION);
+        assert!(protocol.supports(Capabilities::COMPRESSION | Capabilities::GOODBYE));
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Emote {
+        name: String,
+    }
+
+    impl CustomMessage for Emote {
+        const TYPE_ID: u16 = 0x8001;
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Loadout {
+        items: Vec<u32>,
+    }
+
+    impl CustomMessage for Loadout {
+        const TYPE_ID: u16 = 0x8002;
+
+        fn encoding() -> Encoding {
+            Encoding::MessagePack
+        }
+    }
+
+    #[test]
+    fn custom_packets() {
+        // Echo the custom packets back
+        let server = MockServer::start(|packet| match packet.packet_type {
+            PacketType::Custom => vec![Packet::custom(packet.type_id, packet.data.to_vec())],
+            _ => vec![],
+        });
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .build()
+            .unwrap();
+        let emotes = Rc::new(RefCell::new(Vec::new()));
+        let received = emotes.clone();
+        client.on_custom(move |emote: Emote| received.borrow_mut().push(emote));
+        client.connect();
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+
+        client.send_custom(&Emote {
+            name: "wave".to_owned(),
+        });
+        client.send_custom(&Loadout { items: vec![1, 2] });
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::Custom { .. })
+        });
+        // The emote went to its handler
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, LobbyEvent::Custom { type_id, .. } if *type_id == Emote::TYPE_ID)));
+        assert_eq!(
+            *emotes.borrow(),
+            [Emote {
+                name: "wave".to_owned()
+            }]
+        );
+        match events.last() {
+            Some(LobbyEvent::Custom { type_id, payload }) => {
+                assert_eq!(*type_id, Loadout::TYPE_ID);
+                let loadout: Loadout = Encoding::MessagePack.deserialize(payload).unwrap();
+                assert_eq!(loadout.items, [1, 2]);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn lobby_data() {
+        let server = MockServer::start(|packet| match packet.packet_type {
+            PacketType::SendLobbyData => {
+                let msg = packet_to_message::<SendLobbyData>(packet).unwrap();
+                vec![message_to_packet(&NewLobbyData {
+                    lobby_id: "lobby".to_owned(),
+                    profile: auth_success().user_profile.unwrap(),
+                    channel: msg.channel,
+                    payload: msg.payload,
+                })
+                .unwrap()]
+            }
+            _ => vec![],
+        });
+        let mut client = LobbyClientBuilder::new(&server.addr.to_string())
+            .build()
+            .unwrap();
+        client.connect();
+        tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::ConnectionEstablished)
+        });
+
+        let too_large = vec![0; crate::MAX_LOBBY_DATA_SIZE + 1];
+        assert!(client
+            .send_lobby_data("vote".to_owned(), too_large, None)
+            .is_err());
+        client
+            .send_lobby_data("vote".to_owned(), vec![3], Some("user#1234".to_owned()))
+            .unwrap();
+        let events = tick_until(&mut client, |event| {
+            matches!(event, LobbyEvent::LobbyDataReceived { .. })
+        });
+        match events.last() {
+            Some(LobbyEvent::LobbyDataReceived {
+                channel, payload, ..
+            }) => {
+                assert_eq!(channel, "vote");
+                assert_eq!(payload, &[3]);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+
+        let sent: Vec<SendLobbyData> = server
+            .received()
+            .iter()
+            .filter(|packet| packet.packet_type == PacketType::SendLobbyData)
+            .map(|packet| packet_to_message(packet).unwrap())
+            .collect();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].target.as_deref(), Some("user#1234"));
+    }
 }
@@ -1,17 +1,23 @@
-use crate::net::packet::{message_to_packet, packet_to_message, Packet};
-use crate::net::packet_decoder::PacketDecoder;
+use crate::net::legacy;
+use crate::net::packet::{message_to_packet, packet_to_message, Packet, MAX_PACKET_SIZE};
+use crate::net::packet_decoder::{Frame, PacketDecoder, UnknownPacket, READ_SIZE};
 use crate::net::packet_encoder::PacketEncoder;
 use crate::net::packets::*;
-use crate::net::transport::tcp_socket::TcpSocket;
-use crate::utils::buffer_processor::BufferProcessor;
+use crate::net::protocol::{Capabilities, NegotiatedProtocol};
+use crate::net::socket::Socket;
+use crate::net::transport;
+use crate::net::transport::tls::TlsConfig;
+use crate::net::transport::websocket::WebSocketConfig;
+use crate::net::transport::{Connector, Transport};
+use crate::net::ErrorKind;
+use crate::utils::buffer_processor::{
+    BufferProcessor, BufferProcessorFactory, CompressionHandle, CompressionProcessor,
+};
+use crate::utils::byte_buffer::ReadBuffer;
 use crate::utils::time;
-use crate::{net, ErrorCode, LobbyEvent};
-use bytes::Bytes;
+use crate::{net, DisconnectReason, ErrorCode, LobbyEvent};
 use log::{debug, error};
-use mio::net::TcpStream;
-use std::io::{Read, Write};
-use std::net::{Shutdown, SocketAddr};
-use std::str::FromStr;
+use std::net::SocketAddr;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{io, mem};
 
@@ -21,9 +27,50 @@ pub enum ConnState {
     Initializing,
     Authenticating,
     Running,
+    /// Goodbye sent, waiting for the server to acknowledge it
+    Closing,
     Closed,
 }
 
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_GOODBYE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Settings applied to every new connection
+#[derive(Clone)]
+pub struct ConnectionConfig {
+    pub tls: Option<TlsConfig>,
+    /// Carry the packets in WebSocket frames
+    pub websocket: Option<WebSocketConfig>,
+    /// Compress buffers bigger than this threshold, if the server supports it
+    pub compression_threshold: Option<usize>,
+    pub buffer_processors: Vec<BufferProcessorFactory>,
+    /// Open the transport of new connections, instead of connecting over TCP
+    /// with the TLS and WebSocket settings above
+    pub connector: Option<Connector>,
+    /// Time allowed to establish the transport
+    pub connect_timeout: Option<Duration>,
+    /// Time allowed for the server `PacketInitResponse` to arrive, once the transport is established
+    pub handshake_timeout: Option<Duration>,
+    /// Time allowed for the server to acknowledge a goodbye, before closing anyway
+    pub goodbye_timeout: Duration,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            tls: None,
+            websocket: None,
+            compression_threshold: None,
+            buffer_processors: Vec::new(),
+            connector: None,
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            goodbye_timeout: DEFAULT_GOODBYE_TIMEOUT,
+        }
+    }
+}
+
 pub struct PeerInfo {
     pub addr: SocketAddr,
 }
@@ -38,39 +85,108 @@ pub struct Connection {
     pub token: mio::Token,
     pub peer_info: PeerInfo,
     pub state: ConnState,
+    pub opened_time: Instant,
+    pub connected_time: Option<Instant>,
+    pub closing_time: Option<Instant>,
     pub closed_time: Option<Instant>,
+    /// Why the connection was closed, if it wasn't on purpose
+    pub close_reason: Option<String>,
+    connect_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    goodbye_timeout: Duration,
 
-    pub socket: TcpSocket,
+    pub socket: Socket,
+    /// Received data waiting for the buffer processors, reused once they are done with it
+    read_buffer: ReadBuffer,
+    /// Reading stopped before the socket would block, see `read`
+    read_pending: bool,
     pub tcp_encoder: PacketEncoder,
     pub tcp_decoder: PacketDecoder,
+    pub compression: Option<CompressionHandle>,
+    /// Sent during the handshake while compression may be picked, see `CompressionProcessor`
+    held: Vec<Packet>,
+    /// Set once the server answered the handshake
+    pub protocol: Option<NegotiatedProtocol>,
+    /// Advertised to the server
+    capabilities: Capabilities,
 
     events: Vec<LobbyEvent>,
 }
 
 impl Connection {
     /// Create the connection and issue non blocking connect
-    pub fn open(addr: SocketAddr, token: mio::Token) -> io::Result<Self> {
-        let stream = TcpStream::connect(addr)?;
-        let mut socket = TcpSocket::new(stream);
+    pub fn open(
+        addr: SocketAddr,
+        token: mio::Token,
+        config: &ConnectionConfig,
+    ) -> io::Result<Self> {
+        let transport = transport::connect(addr, config)?;
+        Ok(Self::with_transport(transport, addr, token, config))
+    }
+
+    /// Create the connection on top of an already opened transport
+    pub fn with_transport(
+        transport: Box<dyn Transport>,
+        addr: SocketAddr,
+        token: mio::Token,
+        config: &ConnectionConfig,
+    ) -> Self {
         let mut conn = Self {
             token,
             peer_info: PeerInfo::new(addr),
             state: ConnState::Initializing,
+            opened_time: Instant::now(),
+            connected_time: None,
+            closing_time: None,
             closed_time: None,
-            socket,
+            close_reason: None,
+            connect_timeout: config.connect_timeout,
+            handshake_timeout: config.handshake_timeout,
+            goodbye_timeout: config.goodbye_timeout,
+            socket: Socket::new(transport),
+            read_buffer: ReadBuffer::default(),
+            read_pending: false,
             tcp_encoder: PacketEncoder::new(8 * 1024),
             tcp_decoder: PacketDecoder::new(),
+            compression: None,
+            held: Vec::new(),
+            protocol: None,
+            capabilities: Capabilities::GOODBYE,
             events: Vec::new(),
         };
+        // Compress before any user processor (e.g. encryption) gets the data
+        if let Some(threshold) = config.compression_threshold {
+            let processor = CompressionProcessor::new(threshold);
+            conn.compression = Some(processor.handle());
+            conn.add_buffer_processor(Box::new(processor));
+            conn.capabilities |= Capabilities::COMPRESSION;
+        }
+        for factory in &config.buffer_processors {
+            conn.add_buffer_processor(factory());
+        }
         // Init handshake
         conn.send(
             message_to_packet(&PacketInit {
-                protocol_version: crate::PROTOCOL_VERSION,
+                min_protocol_version: crate::MIN_PROTOCOL_VERSION,
                 app_version: crate::APP_VERSION,
+                max_protocol_version: crate::PROTOCOL_VERSION,
+                capabilities: conn.capabilities.bits(),
             })
             .unwrap(),
         );
-        Ok(conn)
+        conn
+    }
+
+    /// Whether the server agreed to use the given features
+    pub fn supports(&self, capabilities: Capabilities) -> bool {
+        self.protocol
+            .is_some_and(|protocol| protocol.supports(capabilities))
+    }
+
+    /// Whether the server speaks protocol version 1, see `net::legacy`
+    fn is_legacy(&self) -> bool {
+        self.protocol
+            .is_some_and(|protocol| protocol.version == legacy::PROTOCOL_VERSION)
     }
 
     /// Add a buffer processor to be executed when sending and receiving buffers.
@@ -83,6 +199,29 @@ impl Connection {
     }
 
     pub fn send(&mut self, packet: Packet) {
+        if self.state == ConnState::Closing {
+            debug!("Said goodbye already, dropping {:?}", packet.packet_type);
+            return;
+        }
+        if packet.data_size() > MAX_PACKET_SIZE {
+            error!(
+                "Dropping {:?} of {} bytes, larger than {} bytes",
+                packet.packet_type,
+                packet.data_size(),
+                MAX_PACKET_SIZE
+            );
+            return;
+        }
+        // Whether to compress what follows the `PacketInit` is only known from the answer.
+        // A `FatalError` is sent as is, the connection being closed right after.
+        if self.state == ConnState::Initializing
+            && self.compression.is_some()
+            && packet.packet_type != PacketType::PacketInit
+            && packet.packet_type != PacketType::FatalError
+        {
+            self.held.push(packet);
+            return;
+        }
         self.tcp_encoder.add_packet(packet);
     }
 
@@ -99,19 +238,32 @@ impl Connection {
         while let Some(buffer) = self.tcp_encoder.next_buffer() {
             self.socket.unprocessed_out.push_back(buffer);
         }
-        self.socket.process_out();
+        if let Err(err) = self.socket.process_out() {
+            self.processing_failed(err);
+            return;
+        }
 
         if !self.socket.processed_out.is_empty() {
             self.write();
         }
 
         // In
-        self.socket.process_in();
+        if let Err(err) = self.socket.process_in() {
+            self.processing_failed(err);
+            return;
+        }
         while let Some(buffer) = self.socket.processed_in.pop_front() {
             self.tcp_decoder.push_buffer(buffer);
         }
-        while let Some(packet) = self.tcp_decoder.next_packet() {
-            self.incoming_packet(packet);
+        while self.state != ConnState::Closed {
+            match self.tcp_decoder.next_frame() {
+                Some(Frame::Packet(packet)) => self.incoming_packet(packet),
+                Some(Frame::Unknown(unknown)) => self.unknown_packet(unknown),
+                Some(Frame::Malformed(message)) => {
+                    self.disconnect(DisconnectReason::ProtocolError { message })
+                }
+                None => break,
+            }
         }
     }
 
@@ -121,190 +273,1313 @@ impl Connection {
         self.closed_time = Some(Instant::now());
     }
 
-    /// Read as much as possible from the connection's socket.
-    /// Buffers are read into the given buffer, and pushed to be processed.
-    pub fn read(&mut self, read_buffer: &mut [u8]) -> io::Result<()> {
+    /// Close gracefully: the packets queued so far are sent, followed by a goodbye,
+    /// and the connection is closed once the server acknowledges it (or after `goodbye_timeout`)
+    pub fn goodbye(&mut self, reason: &str) {
+        match self.state {
+            ConnState::Closing | ConnState::Closed => return,
+            _ if !self.socket.is_connected() => {
+                // Nobody to say goodbye to yet
+                self.close();
+                return;
+            }
+            _ if !self.supports(Capabilities::GOODBYE) => {
+                // The server wouldn't acknowledge, send what's queued and leave
+                self.flush();
+                self.close();
+                self.events.push(LobbyEvent::Disconnected {
+                    reason: DisconnectReason::Requested,
+                });
+                return;
+            }
+            _ => {}
+        }
+        self.send(
+            message_to_packet(&Goodbye {
+                reason: reason.to_owned(),
+            })
+            .unwrap(),
+        );
+        self.state = ConnState::Closing;
+        self.closing_time = Some(Instant::now());
+        self.flush();
+    }
+
+    /// The connection was closed by the peer or failed: handle what was received until then,
+    /// and report the disconnection if the connection had been established, i.e. the handshake
+    /// completed. Otherwise, once the transport connected, the connection attempt failed.
+    pub fn lost(&mut self, reason: String) {
+        let connected = self.socket.is_connected();
+        if self.state != ConnState::Closed && connected {
+            self.flush();
+        }
+        let state = self.state;
+        if state == ConnState::Closed {
+            return;
+        }
+        self.close();
+        match state {
+            // The server closed the connection instead of acknowledging, good enough
+            ConnState::Closing => self.events.push(LobbyEvent::Disconnected {
+                reason: DisconnectReason::Requested,
+            }),
+            // Transport failures are reported when dialing
+            ConnState::Initializing if connected => self.events.push(LobbyEvent::ConnectFailed {
+                reason: reason.clone(),
+            }),
+            ConnState::Initializing => {}
+            _ => self.events.push(LobbyEvent::Disconnected {
+                reason: DisconnectReason::ConnectionLost {
+                    message: reason.clone(),
+                },
+            }),
+        }
+        self.close_reason = Some(reason);
+    }
+
+    /// Close the connection if it is taking too long to connect, to complete the handshake,
+    /// or for the server to acknowledge the goodbye. Returns whether it timed out.
+    pub fn check_timeouts(&mut self) -> bool {
+        match (self.state, self.closing_time) {
+            (ConnState::Closing, Some(closing_time))
+                if closing_time.elapsed() >= self.goodbye_timeout =>
+            {
+                debug!("Goodbye to {} not acknowledged", self.peer_info.addr);
+                self.close();
+                self.events.push(LobbyEvent::Disconnected {
+                    reason: DisconnectReason::Requested,
+                });
+                return true;
+            }
+            (ConnState::Initializing, _) => {}
+            _ => return false,
+        }
+        match (
+            self.connected_time,
+            self.connect_timeout,
+            self.handshake_timeout,
+        ) {
+            (None, Some(timeout), _) if self.opened_time.elapsed() >= timeout => {
+                error!("Connect to {} timed out", self.peer_info.addr);
+                self.close();
+                self.close_reason = Some("Connect timed out".to_owned());
+                true
+            }
+            (Some(connected_time), _, Some(timeout)) if connected_time.elapsed() >= timeout => {
+                error!("Handshake with {} timed out", self.peer_info.addr);
+                self.close();
+                self.close_reason = Some("Handshake timed out".to_owned());
+                self.events.push(LobbyEvent::HandshakeTimedOut);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Read until the socket would block. Without buffer processors, the data goes straight
+    /// into the decoder, otherwise into buffers split off `read_buffer` for the processors.
+    /// Stops once more than the largest packet is waiting to be decoded: the socket
+    /// is left readable and the reading resumes after a flush, see `read_pending`.
+    pub fn read(&mut self) -> io::Result<()> {
+        self.read_pending = false;
         loop {
-            let res = self.socket.stream.read(read_buffer);
-            if let Ok(n) = res {
-                debug!("Read {} bytes", n);
-                if n > 0 {
-                    debug!("Read buffer: {:?}", &read_buffer[..n]);
-                    self.socket
-                        .unprocessed_in
-                        .push_back(Bytes::copy_from_slice(&read_buffer[..n]).into())
-                } else {
-                    return Err(io::ErrorKind::ConnectionAborted.into());
-                }
+            if self.buffered() > MAX_PACKET_SIZE {
+                self.read_pending = true;
+                return Ok(());
+            }
+            let socket = &mut self.socket;
+            let n = if socket.has_buffer_processors() {
+                let n = self
+                    .read_buffer
+                    .read_from(READ_SIZE, |buf| socket.read(buf))?;
+                let buffer = self.read_buffer.split_to(n).freeze();
+                debug!("Read buffer: {:?}", &buffer[..]);
+                socket.unprocessed_in.push_back(buffer.into());
+                n
             } else {
-                return res.map(|_| ());
+                self.tcp_decoder.read_from(|buf| socket.read(buf))?
+            };
+            debug!("Read {} bytes", n);
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "Connection closed by peer",
+                ));
             }
         }
     }
 
+    /// Whether `read` stopped before the socket would block
+    pub fn read_pending(&self) -> bool {
+        self.read_pending && self.state != ConnState::Closed
+    }
+
+    /// Bytes received but not decoded yet
+    fn buffered(&self) -> usize {
+        let unprocessed: usize = self.socket.unprocessed_in.iter().map(|b| b.len()).sum();
+        let processed: usize = self.socket.processed_in.iter().map(|b| b.len()).sum();
+        unprocessed + processed + self.tcp_decoder.buffered()
+    }
+
     /// Process out buffers and write as much as possible to the connection's socket.
     pub fn write(&mut self) -> io::Result<()> {
         if !self.socket.is_connected() {
-            // Connected (i.e we received the first write event after connect)
-            self.socket.connected();
-            debug!("Set nodelay for connection {}", self.token.0);
+            // First write event after connect, check whether it succeeded
+            match self.socket.connected() {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Err(err),
+                Err(err) => {
+                    error!("Could not connect to {}: {}", self.peer_info.addr, err);
+                    self.close();
+                    self.close_reason = Some(err.to_string());
+                    return Err(err);
+                }
+            }
+            self.connected_time = Some(Instant::now());
+            debug!("Connection {} established", self.token.0);
         }
 
-        self.socket.process_out();
+        self.socket.flush()?;
+        if let Err(err) = self.socket.process_out() {
+            self.processing_failed(err);
+            return Err(io::ErrorKind::InvalidData.into());
+        }
         debug!(
             "Writable, processed_out len: {}",
             self.socket.processed_out.len()
         );
-        while let Some(buffer) = self.socket.processed_out.front_mut() {
-            let res = self.socket.stream.write(&buffer[..]);
+        while let Some(mut buffer) = self.socket.processed_out.pop_front() {
+            let res = self.socket.write(&buffer[..]);
             match res {
                 Ok(n) if n < buffer.len() => {
                     debug!("Written {} bytes, truncating buffer", n);
                     buffer.skip(n);
+                    self.socket.processed_out.push_front(buffer);
                 }
                 Ok(n) => {
                     debug!("Written {} bytes", n);
-                    self.socket.processed_out.pop_front();
                 }
-                _ => return res.map(|_| ()),
+                _ => {
+                    self.socket.processed_out.push_front(buffer);
+                    return res.map(|_| ());
+                }
             }
         }
         Ok(())
     }
 
+    /// Packet from a newer version of the protocol
+    fn unknown_packet(&mut self, unknown: UnknownPacket) {
+        if unknown.critical {
+            self.disconnect(DisconnectReason::ProtocolError {
+                message: format!("Unknown critical packet type {}", unknown.type_id),
+            });
+            return;
+        }
+        debug!("Skipped unknown packet type {}", unknown.type_id);
+        self.events.push(LobbyEvent::UnknownPacket {
+            type_id: unknown.type_id,
+            size: unknown.size,
+        });
+    }
+
     fn incoming_packet(&mut self, packet: Packet) {
         debug!("Handling packet {:?}", packet.packet_type);
+        if packet.packet_type == PacketType::Custom {
+            self.events.push(LobbyEvent::Custom {
+                type_id: packet.type_id,
+                payload: packet.data.to_vec(),
+            });
+            return;
+        }
+        let protocol_version = self
+            .protocol
+            .map_or(crate::PROTOCOL_VERSION, |protocol| protocol.version);
+        let result = match self.legacy_packet(&packet) {
+            Some(result) => result,
+            None => dispatch(self, &packet, protocol_version),
+        };
+        match result {
+            Ok(()) => {}
+            Err(err) => match *err {
+                ErrorKind::InvalidPacketType(packet_type) => {
+                    error!("Received unexpected packet type: {:?}", packet_type);
+                }
+                err => self.disconnect(DisconnectReason::ProtocolError {
+                    message: format!("{:?}", err),
+                }),
+            },
+        }
+    }
+
+    /// Handle the packets whose layout changed since protocol version 1, when sent in the version 1
+    /// layout. Returns `None` for the packets to dispatch as usual.
+    fn legacy_packet(&mut self, packet: &Packet) -> Option<net::Result<()>> {
         match packet.packet_type {
+            // Only sent by the servers which don't negotiate
             PacketType::PacketInit => {
-                let msg = packet_to_message::<PacketInit>(&packet).unwrap();
-                if msg.app_version != crate::APP_VERSION {
-                    self.disconnect("Invalid app version");
-                    return;
-                }
-                if msg.protocol_version != crate::PROTOCOL_VERSION {
-                    self.disconnect("Invalid protocol version");
-                    return;
-                }
-                self.state = ConnState::Authenticating;
-                self.events.push(LobbyEvent::ConnectionEstablished);
-            }
-            PacketType::FatalError => {
-                let msg = packet_to_message::<FatalError>(&packet).unwrap();
-                self.disconnect(&msg.message);
-                return;
-            }
-            PacketType::PacketPing => {
-                let msg = packet_to_message::<PacketPing>(&packet).unwrap();
-                self.send(
-                    message_to_packet(&PacketPong {
-                        id: msg.id,
-                        peer_time: time::unix_millis(),
-                    })
-                    .unwrap(),
-                );
-                self.flush();
+                Some(legacy::from_packet(packet).map(|msg| self.legacy_init(msg)))
             }
-            PacketType::AuthenticationResponse => {
-                let msg = packet_to_message::<AuthenticationResponse>(&packet).unwrap();
-                match msg {
-                    AuthenticationResponse {
-                        error_code: Some(err),
-                        session_token: None,
-                        user_profile: None,
-                    } => self.events.push(LobbyEvent::AuthFailure {
-                        error_code: ErrorCode::from_str(&err).expect("unknown error code"),
-                    }),
-                    AuthenticationResponse {
-                        error_code: None,
-                        session_token: Some(session_token),
-                        user_profile: Some(user_profile),
-                    } => self.events.push(LobbyEvent::AuthSuccess {
-                        session_token,
-                        user_profile,
-                    }),
-                    _ => self.disconnect("Protocol error"),
-                }
+            PacketType::PacketPing if self.is_legacy() => {
+                Some(legacy::from_packet(packet).map(|msg| self.legacy_ping(msg)))
             }
-            PacketType::FetchPendingFriendRequestsResponse => {
-                let msg = packet_to_message::<FetchPendingFriendRequestsResponse>(&packet).unwrap();
-                self.events.push(LobbyEvent::FriendRequestsUpdated {
-                    as_inviter: msg.pending_as_inviter,
-                    as_invitee: msg.pending_as_invitee,
-                });
+            PacketType::FatalError if self.is_legacy() => Some(
+                legacy::from_packet::<legacy::FatalError>(packet)
+                    .map(|msg| self.fatal_error(DisconnectReason::from_code(0, msg.message))),
+            ),
+            // Servers of either version may refuse the handshake
+            PacketType::FatalError if self.state == ConnState::Initializing => {
+                let reason = match packet_to_message::<FatalError>(packet) {
+                    Ok(msg) => DisconnectReason::from_code(msg.code, msg.message),
+                    Err(_) => match legacy::from_packet::<legacy::FatalError>(packet) {
+                        Ok(msg) => DisconnectReason::from_code(0, msg.message),
+                        Err(err) => return Some(Err(err)),
+                    },
+                };
+                self.fatal_error(reason);
+                Some(Ok(()))
             }
-            PacketType::FetchFriendListResponse => {
-                let msg = packet_to_message::<FetchFriendListResponse>(&packet).unwrap();
-                self.events.push(LobbyEvent::FriendListUpdated {
-                    friend_list: msg.friend_list,
-                });
-            }
-            PacketType::NewPrivateMessage => {
-                let msg = packet_to_message::<NewPrivateMessage>(&packet).unwrap();
-                self.events.push(LobbyEvent::NewPrivateMessage {
-                    profile: msg.profile,
-                    content: msg.content,
-                    is_self: msg.is_self,
-                });
-            }
-            PacketType::SystemNotification => {
-                let msg = packet_to_message::<SystemNotification>(&packet).unwrap();
-                self.events.push(LobbyEvent::SystemNotification {
-                    content: msg.content,
-                });
-            }
-            PacketType::LobbyInvite => {
-                let msg = packet_to_message::<LobbyInvite>(&packet).unwrap();
-                self.events.push(LobbyEvent::LobbyInvite {
-                    id: msg.id,
-                    inviter: msg.inviter,
-                });
-            }
-            PacketType::LobbyJoined => {
-                let msg = packet_to_message::<LobbyJoined>(&packet).unwrap();
-                self.events.push(LobbyEvent::LobbyJoined {
-                    lobby_id: msg.lobby_id,
-                });
-            }
-            PacketType::LobbyMemberUpdate => {
-                let msg = packet_to_message::<LobbyMemberUpdate>(&packet).unwrap();
-                self.events.push(LobbyEvent::LobbyMemberUpdate {
-                    lobby_id: msg.lobby_id,
-                    members: msg.members,
-                });
+            _ => None,
+        }
+    }
+
+    /// The server answered the handshake with a `PacketInit`: it speaks protocol version 1
+    fn legacy_init(&mut self, msg: legacy::PacketInit) {
+        if self.state != ConnState::Initializing {
+            self.disconnect(DisconnectReason::ProtocolError {
+                message: "Unexpected PacketInit".to_owned(),
+            });
+            return;
+        }
+        // Even to report a mismatch, the server only understands the version 1 layouts
+        self.protocol = Some(NegotiatedProtocol {
+            version: legacy::PROTOCOL_VERSION,
+            capabilities: Capabilities::empty(),
+        });
+        if msg.protocol_version != legacy::PROTOCOL_VERSION || msg.app_version != crate::APP_VERSION
+        {
+            self.disconnect(DisconnectReason::VersionMismatch {
+                message: format!(
+                    "The server speaks protocol version {} and app version {}",
+                    msg.protocol_version, msg.app_version
+                ),
+            });
+            return;
+        }
+        debug!("Server without negotiation, using protocol version 1");
+        self.state = ConnState::Authenticating;
+        self.release_held();
+        self.events.push(LobbyEvent::ConnectionEstablished);
+    }
+
+    /// Send what was held during the handshake
+    fn release_held(&mut self) {
+        if self.held.is_empty() {
+            return;
+        }
+        for packet in mem::take(&mut self.held) {
+            self.send(packet);
+        }
+        self.flush();
+    }
+
+    fn legacy_ping(&mut self, msg: legacy::PacketPing) {
+        let pong = legacy::PacketPong {
+            id: msg.id,
+            peer_time: time::unix_millis(),
+        };
+        self.send(legacy::to_packet(PacketType::PacketPong, &pong).unwrap());
+        self.flush();
+    }
+
+    /// Closed by the server, no need to answer
+    fn fatal_error(&mut self, reason: DisconnectReason) {
+        self.close();
+        self.events.push(LobbyEvent::Disconnected { reason });
+    }
+
+    /// The stream can't be trusted anymore (e.g. decryption failure), close right away
+    fn processing_failed(&mut self, err: net::Error) {
+        error!(
+            "Closing connection due to buffer processing error: {:?}",
+            err
+        );
+        if self.state != ConnState::Closed {
+            self.close();
+            self.events.push(LobbyEvent::Disconnected {
+                reason: DisconnectReason::ProtocolError {
+                    message: format!("Buffer processing error: {:?}", err),
+                },
+            });
+        }
+    }
+
+    /// Report the error to the server, and close
+    fn disconnect(&mut self, reason: DisconnectReason) {
+        if self.socket.is_connected() {
+            error!("Disconnecting: {}", reason);
+            if let Some(code) = reason.code() {
+                let message = reason.message().to_owned();
+                let packet = if self.is_legacy() {
+                    legacy::to_packet(PacketType::FatalError, &legacy::FatalError { message })
+                } else {
+                    message_to_packet(&FatalError { code, message })
+                };
+                self.send(packet.unwrap());
+                self.flush();
             }
-            PacketType::LobbyLeft => {
-                let msg = packet_to_message::<LobbyLeft>(&packet).unwrap();
-                self.events.push(LobbyEvent::LobbyLeft {
-                    lobby_id: msg.lobby_id,
-                });
+            self.close();
+            self.events.push(LobbyEvent::Disconnected { reason });
+        }
+    }
+}
+
+impl HandlePacket<PacketInitResponse> for Connection {
+    fn handle(&mut self, msg: PacketInitResponse) -> net::Result<()> {
+        if self.state != ConnState::Initializing {
+            self.disconnect(DisconnectReason::ProtocolError {
+                message: "Unexpected PacketInitResponse".to_owned(),
+            });
+            return Ok(());
+        }
+        let protocol = NegotiatedProtocol {
+            version: msg.protocol_version,
+            // Ignore anything we didn't ask for
+            capabilities: Capabilities::from_bits_truncate(msg.capabilities) & self.capabilities,
+        };
+        if let Some(compression) = &self.compression {
+            let compressed = protocol.supports(Capabilities::COMPRESSION);
+            if compressed {
+                debug!("Compression enabled");
+                compression.enable();
             }
-            PacketType::NewLobbyMessage => {
-                let msg = packet_to_message::<NewLobbyMessage>(&packet).unwrap();
-                self.events.push(LobbyEvent::NewLobbyMessage {
-                    lobby_id: msg.lobby_id,
-                    profile: msg.profile,
-                    content: msg.content,
+            if compression.compressed_in() != Some(compressed) {
+                self.disconnect(DisconnectReason::ProtocolError {
+                    message: "Compression picked but not applied".to_owned(),
                 });
+                return Ok(());
             }
-            _ => {
-                error!("Received unhandled packet type: {:?}", packet.packet_type);
-            }
         }
+        let supported = crate::MIN_PROTOCOL_VERSION..=crate::PROTOCOL_VERSION;
+        if !supported.contains(&msg.protocol_version) {
+            self.disconnect(DisconnectReason::VersionMismatch {
+                message: format!(
+                    "The server picked protocol version {}, not in {:?}",
+                    msg.protocol_version, supported
+                ),
+            });
+            return Ok(());
+        }
+        debug!("Negotiated {:?}", protocol);
+        self.protocol = Some(protocol);
+        self.state = ConnState::Authenticating;
+        self.release_held();
+        self.events.push(LobbyEvent::ConnectionEstablished);
+        Ok(())
     }
+}
 
-    fn disconnect(&mut self, error_message: &str) {
-        if self.socket.is_connected() {
-            self.send(
-                message_to_packet(&FatalError {
-                    message: error_message.to_owned(),
-                })
-                .unwrap(),
-            );
-            self.flush();
+impl HandlePacket<Goodbye> for Connection {
+    fn handle(&mut self, msg: Goodbye) -> net::Result<()> {
+        self.send(message_to_packet(&GoodbyeAck {}).unwrap());
+        self.flush();
+        self.close();
+        self.events.push(LobbyEvent::Disconnected {
+            reason: DisconnectReason::ServerGoodbye {
+                message: msg.reason,
+            },
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<GoodbyeAck> for Connection {
+    fn handle(&mut self, _msg: GoodbyeAck) -> net::Result<()> {
+        if self.state == ConnState::Closing {
             self.close();
             self.events.push(LobbyEvent::Disconnected {
-                message: error_message.to_owned(),
+                reason: DisconnectReason::Requested,
             });
         }
+        Ok(())
+    }
+}
+
+impl HandlePacket<FatalError> for Connection {
+    fn handle(&mut self, msg: FatalError) -> net::Result<()> {
+        self.fatal_error(DisconnectReason::from_code(msg.code, msg.message));
+        Ok(())
+    }
+}
+
+impl HandlePacket<PacketPing> for Connection {
+    fn handle(&mut self, msg: PacketPing) -> net::Result<()> {
+        self.send(
+            message_to_packet(&PacketPong {
+                id: msg.id,
+                peer_time: time::unix_millis(),
+            })
+            .unwrap(),
+        );
+        self.flush();
+        Ok(())
+    }
+}
+
+impl HandlePacket<AuthenticationResponse> for Connection {
+    fn handle(&mut self, msg: AuthenticationResponse) -> net::Result<()> {
+        match msg {
+            AuthenticationResponse {
+                error_code: Some(err),
+                session_token: None,
+                user_profile: None,
+            } => self.events.push(LobbyEvent::AuthFailure {
+                error_code: ErrorCode::from_code(err),
+            }),
+            AuthenticationResponse {
+                error_code: None,
+                session_token: Some(session_token),
+                user_profile: Some(user_profile),
+            } => self.events.push(LobbyEvent::AuthSuccess {
+                session_token,
+                user_profile,
+            }),
+            _ => self.disconnect(DisconnectReason::ProtocolError {
+                message: format!("Unexpected {}", AuthenticationResponse::INFO.name),
+            }),
+        }
+        Ok(())
+    }
+}
+
+impl HandlePacket<DeviceCodeIssued> for Connection {
+    fn handle(&mut self, msg: DeviceCodeIssued) -> net::Result<()> {
+        self.events.push(LobbyEvent::DeviceCodeIssued {
+            user_code: msg.user_code,
+            verification_uri: msg.verification_uri,
+            expires_in: Duration::from_secs(msg.expires_in as u64),
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<UpdateProfileResponse> for Connection {
+    fn handle(&mut self, msg: UpdateProfileResponse) -> net::Result<()> {
+        match msg {
+            UpdateProfileResponse {
+                error_code: Some(err),
+                user_profile: None,
+            } => self.events.push(LobbyEvent::ProfileUpdateFailure {
+                error_code: ErrorCode::from_code(err),
+            }),
+            UpdateProfileResponse {
+                error_code: None,
+                user_profile: Some(user_profile),
+            } => self
+                .events
+                .push(LobbyEvent::ProfileUpdated { user_profile }),
+            _ => self.disconnect(DisconnectReason::ProtocolError {
+                message: format!("Unexpected {}", UpdateProfileResponse::INFO.name),
+            }),
+        }
+        Ok(())
+    }
+}
+
+impl HandlePacket<FetchProfileResponse> for Connection {
+    fn handle(&mut self, msg: FetchProfileResponse) -> net::Result<()> {
+        self.events.push(LobbyEvent::ProfileFetched {
+            user_tag: msg.user_tag,
+            user_profile: msg.user_profile,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<SearchUsersResponse> for Connection {
+    fn handle(&mut self, msg: SearchUsersResponse) -> net::Result<()> {
+        self.events.push(LobbyEvent::UserSearchResults {
+            query: msg.query,
+            results: msg.results,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<ProfileUpdated> for Connection {
+    fn handle(&mut self, msg: ProfileUpdated) -> net::Result<()> {
+        self.events.push(LobbyEvent::ProfileUpdated {
+            user_profile: msg.user_profile,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<FetchPendingFriendRequestsResponse> for Connection {
+    fn handle(&mut self, msg: FetchPendingFriendRequestsResponse) -> net::Result<()> {
+        self.events.push(LobbyEvent::FriendRequestsUpdated {
+            as_inviter: msg.pending_as_inviter,
+            as_invitee: msg.pending_as_invitee,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<FetchFriendListResponse> for Connection {
+    fn handle(&mut self, msg: FetchFriendListResponse) -> net::Result<()> {
+        self.events.push(LobbyEvent::FriendListUpdated {
+            friend_list: msg.friend_list,
+        });
+        Ok(())
+    }
+}
+
+// Not surfaced as events yet, failures are only logged
+
+impl HandlePacket<AddFriendRequestResponse> for Connection {
+    fn handle(&mut self, msg: AddFriendRequestResponse) -> net::Result<()> {
+        if let Some(err) = msg.error_code {
+            debug!("Friend request to {} failed: {}", msg.user_tag, err);
+        }
+        Ok(())
+    }
+}
+
+impl HandlePacket<FriendRequestActionResponse> for Connection {
+    fn handle(&mut self, msg: FriendRequestActionResponse) -> net::Result<()> {
+        if let Some(err) = msg.error_code {
+            debug!(
+                "Action on friend request {} failed: {}",
+                msg.request_id, err
+            );
+        }
+        Ok(())
+    }
+}
+
+impl HandlePacket<RemoveFriendResponse> for Connection {
+    fn handle(&mut self, msg: RemoveFriendResponse) -> net::Result<()> {
+        if let Some(err) = msg.error_code {
+            debug!("Removing friend failed: {}", err);
+        }
+        Ok(())
+    }
+}
+
+impl HandlePacket<NewPrivateMessage> for Connection {
+    fn handle(&mut self, msg: NewPrivateMessage) -> net::Result<()> {
+        self.events.push(LobbyEvent::NewPrivateMessage {
+            profile: msg.profile,
+            content: msg.content,
+            is_self: msg.is_self,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<SystemNotification> for Connection {
+    fn handle(&mut self, msg: SystemNotification) -> net::Result<()> {
+        self.events.push(LobbyEvent::SystemNotification {
+            content: msg.content,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<LobbyInvite> for Connection {
+    fn handle(&mut self, msg: LobbyInvite) -> net::Result<()> {
+        self.events.push(LobbyEvent::LobbyInvite {
+            id: msg.id,
+            inviter: msg.inviter,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<LobbyJoined> for Connection {
+    fn handle(&mut self, msg: LobbyJoined) -> net::Result<()> {
+        self.events.push(LobbyEvent::LobbyJoined {
+            lobby_id: msg.lobby_id,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<LobbyMemberUpdate> for Connection {
+    fn handle(&mut self, msg: LobbyMemberUpdate) -> net::Result<()> {
+        self.events.push(LobbyEvent::LobbyMemberUpdate {
+            lobby_id: msg.lobby_id,
+            members: msg.members,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<LobbyLeft> for Connection {
+    fn handle(&mut self, msg: LobbyLeft) -> net::Result<()> {
+        self.events.push(LobbyEvent::LobbyLeft {
+            lobby_id: msg.lobby_id,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<NewLobbyMessage> for Connection {
+    fn handle(&mut self, msg: NewLobbyMessage) -> net::Result<()> {
+        self.events.push(LobbyEvent::NewLobbyMessage {
+            lobby_id: msg.lobby_id,
+            profile: msg.profile,
+            content: msg.content,
+        });
+        Ok(())
+    }
+}
+
+impl HandlePacket<NewLobbyData> for Connection {
+    fn handle(&mut self, msg: NewLobbyData) -> net::Result<()> {
+        self.events.push(LobbyEvent::LobbyDataReceived {
+            lobby_id: msg.lobby_id,
+            profile: msg.profile,
+            channel: msg.channel,
+            payload: msg.payload,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::connection::{ConnState, Connection, ConnectionConfig};
+    use crate::net::legacy;
+    use crate::net::packet::{
+        message_to_packet, message_to_packet_for, packet_to_message, Packet, PacketFlag,
+        MAX_PACKET_SIZE,
+    };
+    use crate::net::packet_decoder::PacketDecoder;
+    use crate::net::packet_encoder::PacketEncoder;
+    use crate::net::packets::{
+        FatalError, Goodbye, LobbyJoined, NewLobbyMessage, PacketInit, PacketInitResponse,
+        PacketType, SearchUsers, SystemNotification, UpdateProfileResponse,
+    };
+    use crate::net::protocol::{Capabilities, NegotiatedProtocol};
+    use crate::net::transport::memory::{MemoryControl, MemoryTransport};
+    use crate::net::transport::Transport;
+    use crate::utils::buffer_processor::{BufferProcessor, CompressionProcessor, Direction};
+    use crate::{CloseCode, DisconnectReason, ErrorCode, LobbyEvent};
+    use bincode::Options;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    fn open() -> (Connection, MemoryControl, MemoryTransport) {
+        open_with(&ConnectionConfig::default())
+    }
+
+    fn open_with(config: &ConnectionConfig) -> (Connection, MemoryControl, MemoryTransport) {
+        let (client, server) = MemoryTransport::pair();
+        let control = client.control();
+        let conn = Connection::with_transport(
+            Box::new(client),
+            "127.0.0.1:4000".parse().unwrap(),
+            mio::Token(0),
+            config,
+        );
+        (conn, control, server)
+    }
+
+    fn server_send(server: &mut MemoryTransport, packet: Packet) {
+        let mut encoder = PacketEncoder::new(8 * 1024);
+        encoder.add_packet(packet);
+        while let Some(buffer) = encoder.next_buffer() {
+            server.write_all(&buffer).unwrap();
+        }
+    }
+
+    fn server_receive(server: &mut MemoryTransport) -> Vec<Packet> {
+        let mut decoder = PacketDecoder::new();
+        let mut buffer = [0; 1024];
+        while let Ok(n) = server.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            decoder.push_buffer(buffer[..n].to_vec().into());
+        }
+        let mut packets = Vec::new();
+        while let Some(packet) = decoder.next_packet() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    fn init_packet() -> Packet {
+        init_response(crate::PROTOCOL_VERSION, Capabilities::all())
+    }
+
+    fn init_response(protocol_version: u16, capabilities: Capabilities) -> Packet {
+        message_to_packet(&PacketInitResponse {
+            protocol_version,
+            capabilities: capabilities.bits(),
+        })
+        .unwrap()
+    }
+
+    /// Complete the handshake, the server accepting the given capabilities
+    fn establish(conn: &mut Connection, server: &mut MemoryTransport, capabilities: Capabilities) {
+        conn.flush();
+        server_receive(server);
+        server_send(server, init_response(crate::PROTOCOL_VERSION, capabilities));
+        receive(conn);
+    }
+
+    /// Read until the transport would block, then handle the received packets
+    fn receive(conn: &mut Connection) -> Vec<LobbyEvent> {
+        let err = conn.read().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        conn.flush();
+        conn.drain_events()
+    }
+
+    #[test]
+    fn handshake_in_chunks() {
+        let (mut conn, control, mut server) = open();
+        control.set_write_chunk(Some(5));
+        control.set_read_chunk(Some(3));
+
+        conn.flush();
+        let packets = server_receive(&mut server);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].packet_type, PacketType::PacketInit);
+
+        server_send(&mut server, init_packet());
+        assert!(matches!(
+            receive(&mut conn)[..],
+            [LobbyEvent::ConnectionEstablished]
+        ));
+        assert_eq!(conn.state, ConnState::Authenticating);
+    }
+
+    #[test]
+    fn write_would_block() {
+        let (mut conn, control, mut server) = open();
+        control.fail_write(io::ErrorKind::WouldBlock);
+        conn.flush();
+        assert!(server_receive(&mut server).is_empty());
+        assert_eq!(conn.socket.processed_out.len(), 1);
+
+        // Next writable event
+        conn.write().unwrap();
+        assert!(conn.socket.processed_out.is_empty());
+        assert_eq!(server_receive(&mut server).len(), 1);
+    }
+
+    #[test]
+    fn delayed_delivery() {
+        let (mut conn, _, mut server) = open();
+        conn.flush();
+        let server_control = server.control();
+        server_control.pause();
+        server_send(&mut server, init_packet());
+        assert!(receive(&mut conn).is_empty());
+        assert_eq!(conn.state, ConnState::Initializing);
+
+        server_control.resume();
+        assert!(matches!(
+            receive(&mut conn)[..],
+            [LobbyEvent::ConnectionEstablished]
+        ));
+    }
+
+    #[test]
+    fn peer_closed() {
+        let (mut conn, _, mut server) = open();
+        conn.flush();
+        server.close();
+        let err = conn.read().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[test]
+    fn close_while_connecting() {
+        let (mut conn, _, server) = open();
+        conn.close();
+        assert!(server.is_peer_closed());
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let (mut conn, _, _server) = open_with(&ConnectionConfig {
+            connect_timeout: Some(Duration::from_millis(0)),
+            ..ConnectionConfig::default()
+        });
+        assert!(conn.check_timeouts());
+        assert_eq!(conn.state, ConnState::Closed);
+        assert_eq!(conn.close_reason.as_deref(), Some("Connect timed out"));
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let (mut conn, _, _server) = open_with(&ConnectionConfig {
+            handshake_timeout: Some(Duration::from_millis(0)),
+            ..ConnectionConfig::default()
+        });
+        conn.flush();
+        assert!(conn.check_timeouts());
+        assert_eq!(conn.state, ConnState::Closed);
+        assert!(matches!(
+            conn.drain_events()[..],
+            [LobbyEvent::HandshakeTimedOut]
+        ));
+        // Only reported once
+        assert!(!conn.check_timeouts());
+    }
+
+    #[test]
+    fn no_timeout_once_established() {
+        let (mut conn, _, mut server) = open_with(&ConnectionConfig {
+            handshake_timeout: Some(Duration::from_millis(0)),
+            ..ConnectionConfig::default()
+        });
+        conn.flush();
+        server_send(&mut server, init_packet());
+        receive(&mut conn);
+        assert!(!conn.check_timeouts());
+        assert_ne!(conn.state, ConnState::Closed);
+    }
+
+    #[test]
+    fn goodbye_timeout() {
+        let (mut conn, _, mut server) = open_with(&ConnectionConfig {
+            goodbye_timeout: Duration::from_millis(0),
+            ..ConnectionConfig::default()
+        });
+        establish(&mut conn, &mut server, Capabilities::GOODBYE);
+        conn.send(Packet::new(PacketType::FetchFriendList, vec![]));
+        conn.goodbye("Quit");
+        assert_eq!(conn.state, ConnState::Closing);
+        let packets = server_receive(&mut server);
+        let types: Vec<PacketType> = packets.iter().map(|packet| packet.packet_type).collect();
+        assert_eq!(types, [PacketType::FetchFriendList, PacketType::Goodbye]);
+
+        assert!(conn.check_timeouts());
+        assert_eq!(conn.state, ConnState::Closed);
+        assert!(matches!(
+            &conn.drain_events()[..],
+            [LobbyEvent::Disconnected {
+                reason: DisconnectReason::Requested
+            }]
+        ));
+    }
+
+    #[test]
+    fn server_goodbye() {
+        let (mut conn, _, mut server) = open();
+        conn.flush();
+        server_send(&mut server, init_packet());
+        receive(&mut conn);
+        server_send(
+            &mut server,
+            message_to_packet(&Goodbye {
+                reason: "Maintenance".to_owned(),
+            })
+            .unwrap(),
+        );
+        let events = receive(&mut conn);
+        assert_eq!(conn.state, ConnState::Closed);
+        match &events[..] {
+            [LobbyEvent::Disconnected {
+                reason: DisconnectReason::ServerGoodbye { message },
+            }] => assert_eq!(message, "Maintenance"),
+            events => panic!("Unexpected events {:?}", events),
+        }
+        let packets = server_receive(&mut server);
+        assert_eq!(packets.last().unwrap().packet_type, PacketType::GoodbyeAck);
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let (mut conn, _, mut server) = open();
+        conn.flush();
+        server_receive(&mut server);
+        server_send(
+            &mut server,
+            init_response(crate::PROTOCOL_VERSION + 1, Capabilities::all()),
+        );
+        let events = receive(&mut conn);
+        assert_eq!(conn.state, ConnState::Closed);
+        assert!(matches!(
+            &events[..],
+            [LobbyEvent::Disconnected {
+                reason: DisconnectReason::VersionMismatch { .. }
+            }]
+        ));
+        let packets = server_receive(&mut server);
+        let error = packet_to_message::<FatalError>(&packets[0]).unwrap();
+        assert_eq!(error.code, CloseCode::VersionMismatch as u16);
+    }
+
+    #[test]
+    fn malformed_packet() {
+        let (mut conn, _, mut server) = open();
+        conn.flush();
+        server_receive(&mut server);
+        server_send(
+            &mut server,
+            Packet::new(PacketType::PacketInitResponse, vec![0xff]),
+        );
+        let events = receive(&mut conn);
+        assert_eq!(conn.state, ConnState::Closed);
+        assert!(matches!(
+            &events[..],
+            [LobbyEvent::Disconnected {
+                reason: DisconnectReason::ProtocolError { .. }
+            }]
+        ));
+        let packets = server_receive(&mut server);
+        let error = packet_to_message::<FatalError>(&packets[0]).unwrap();
+        assert_eq!(error.code, CloseCode::ProtocolError as u16);
+    }
+
+    #[test]
+    fn negotiation() {
+        let (mut conn, _, mut server) = open();
+        conn.flush();
+        let init = packet_to_message::<PacketInit>(&server_receive(&mut server)[0]).unwrap();
+        assert_eq!(init.min_protocol_version, crate::MIN_PROTOCOL_VERSION);
+        assert_eq!(init.max_protocol_version, crate::PROTOCOL_VERSION);
+        // No compression configured
+        assert_eq!(init.capabilities, Capabilities::GOODBYE.bits());
+
+        // Unknown and unrequested capabilities are ignored
+        server_send(
+            &mut server,
+            message_to_packet(&PacketInitResponse {
+                protocol_version: crate::PROTOCOL_VERSION,
+                capabilities: u32::MAX,
+            })
+            .unwrap(),
+        );
+        receive(&mut conn);
+        let protocol = conn.protocol.unwrap();
+        assert_eq!(protocol.version, crate::PROTOCOL_VERSION);
+        assert_eq!(protocol.capabilities, Capabilities::GOODBYE);
+        assert!(conn.supports(Capabilities::GOODBYE));
+        assert!(!conn.supports(Capabilities::COMPRESSION));
+    }
+
+    /// Pass the buffers through the processor
+    fn process(processor: &mut CompressionProcessor, data: &[u8], direction: Direction) -> Vec<u8> {
+        let mut output = VecDeque::new();
+        processor
+            .process(data.to_vec().into(), &mut output, direction)
+            .unwrap();
+        output.iter().flat_map(|buffer| buffer.to_vec()).collect()
+    }
+
+    #[test]
+    fn compression_negotiation() {
+        let config = ConnectionConfig {
+            compression_threshold: Some(16),
+            ..ConnectionConfig::default()
+        };
+        let (mut conn, _, mut server) = open_with(&config);
+        conn.flush();
+        let init = packet_to_message::<PacketInit>(&server_receive(&mut server)[0]).unwrap();
+        assert!(
+            Capabilities::from_bits_truncate(init.capabilities).contains(Capabilities::COMPRESSION)
+        );
+
+        // Held until the server tells whether to compress
+        let query = "a".repeat(64);
+        conn.send(message_to_packet(&SearchUsers { query }).unwrap());
+        conn.flush();
+        assert!(server_receive(&mut server).is_empty());
+
+        // The server compresses everything from its answer on
+        let mut compression = CompressionProcessor::new(16);
+        compression.handle().enable();
+        let mut encoder = PacketEncoder::new(1024);
+        encoder.add_packet(init_response(crate::PROTOCOL_VERSION, Capabilities::all()));
+        let answer = encoder.next_buffer().unwrap();
+        server
+            .write_all(&process(&mut compression, &answer, Direction::Out))
+            .unwrap();
+        let events = receive(&mut conn);
+        assert!(matches!(&events[..], [LobbyEvent::ConnectionEstablished]));
+        assert!(conn.supports(Capabilities::COMPRESSION));
+
+        let mut wire = vec![0; 1024];
+        let n = server.read(&mut wire).unwrap();
+        let mut decoder = PacketDecoder::new();
+        decoder.push_buffer(process(&mut compression, &wire[..n], Direction::In).into());
+        let search = packet_to_message::<SearchUsers>(&decoder.next_packet().unwrap()).unwrap();
+        assert_eq!(search.query, "a".repeat(64));
+        assert!(compression.handle().stats().ratio_in() < 1.0);
+    }
+
+    #[test]
+    fn compression_not_picked() {
+        let config = ConnectionConfig {
+            compression_threshold: Some(16),
+            ..ConnectionConfig::default()
+        };
+        let (mut conn, _, mut server) = open_with(&config);
+        conn.send(
+            message_to_packet(&SearchUsers {
+                query: "a".repeat(64),
+            })
+            .unwrap(),
+        );
+        establish(&mut conn, &mut server, Capabilities::GOODBYE);
+        assert!(!conn.supports(Capabilities::COMPRESSION));
+        let packets = server_receive(&mut server);
+        assert_eq!(packets[0].packet_type, PacketType::SearchUsers);
+
+        // Picked, but the answer isn't compressed
+        let (mut conn, _, mut server) = open_with(&config);
+        let events = {
+            conn.flush();
+            server_receive(&mut server);
+            server_send(
+                &mut server,
+                init_response(crate::PROTOCOL_VERSION, Capabilities::all()),
+            );
+            receive(&mut conn)
+        };
+        assert!(matches!(
+            &events[..],
+            [LobbyEvent::Disconnected {
+                reason: DisconnectReason::ProtocolError { .. }
+            }]
+        ));
+    }
+
+    /// Answer the handshake as a server of protocol version 1
+    fn establish_legacy(conn: &mut Connection, server: &mut MemoryTransport) -> Vec<LobbyEvent> {
+        conn.flush();
+        let init = &server_receive(server)[0];
+        // The version 1 layout is a prefix of the client's
+        let init: legacy::PacketInit = bincode::DefaultOptions::new()
+            .allow_trailing_bytes()
+            .deserialize(&init.data[..])
+            .unwrap();
+        assert_eq!(init.protocol_version, legacy::PROTOCOL_VERSION);
+        assert_eq!(init.app_version, crate::APP_VERSION);
+        let answer = legacy::PacketInit {
+            protocol_version: legacy::PROTOCOL_VERSION,
+            app_version: crate::APP_VERSION,
+        };
+        server_send(
+            server,
+            legacy::to_packet(PacketType::PacketInit, &answer).unwrap(),
+        );
+        receive(conn)
+    }
+
+    #[test]
+    fn legacy_server() {
+        let (mut conn, _, mut server) = open();
+        let events = establish_legacy(&mut conn, &mut server);
+        assert!(matches!(events[..], [LobbyEvent::ConnectionEstablished]));
+        assert_eq!(conn.state, ConnState::Authenticating);
+        assert_eq!(
+            conn.protocol,
+            Some(NegotiatedProtocol {
+                version: 1,
+                capabilities: Capabilities::empty(),
+            })
+        );
+
+        // Errors are reported in the version 1 layout
+        server_send(
+            &mut server,
+            Packet::new(PacketType::AuthenticationResponse, vec![0xff]),
+        );
+        receive(&mut conn);
+        assert_eq!(conn.state, ConnState::Closed);
+        let packets = server_receive(&mut server);
+        assert_eq!(packets[0].packet_type, PacketType::FatalError);
+        legacy::from_packet::<legacy::FatalError>(&packets[0]).unwrap();
+    }
+
+    #[test]
+    fn legacy_fatal_error() {
+        let error = || {
+            let error = legacy::FatalError {
+                message: "Banned".to_owned(),
+            };
+            legacy::to_packet(PacketType::FatalError, &error).unwrap()
+        };
+        let expected = DisconnectReason::Other {
+            code: 0,
+            message: "Banned".to_owned(),
+        };
+
+        // Refusing the handshake
+        let (mut conn, _, mut server) = open();
+        conn.flush();
+        server_send(&mut server, error());
+        match &receive(&mut conn)[..] {
+            [LobbyEvent::Disconnected { reason }] => assert_eq!(reason, &expected),
+            events => panic!("Unexpected events {:?}", events),
+        }
+
+        let (mut conn, _, mut server) = open();
+        establish_legacy(&mut conn, &mut server);
+        server_send(&mut server, error());
+        match &receive(&mut conn)[..] {
+            [LobbyEvent::Disconnected { reason }] => assert_eq!(reason, &expected),
+            events => panic!("Unexpected events {:?}", events),
+        }
+        assert_eq!(conn.state, ConnState::Closed);
+    }
+
+    #[test]
+    fn read_bounded() {
+        let (mut conn, _, mut server) = open();
+        establish(&mut conn, &mut server, Capabilities::empty());
+        for _ in 0..40 {
+            let message = NewLobbyMessage {
+                lobby_id: "lobby-1".to_owned(),
+                profile: None,
+                content: "a".repeat(MAX_PACKET_SIZE / 32),
+            };
+            server_send(&mut server, message_to_packet(&message).unwrap());
+        }
+
+        // Stops with the socket still readable, once more than a max packet is buffered
+        conn.read().unwrap();
+        assert!(conn.read_pending());
+        conn.flush();
+        let events = conn.drain_events();
+        assert!(!events.is_empty() && events.len() < 40);
+
+        let events = events.len() + receive(&mut conn).len();
+        assert_eq!(events, 40);
+        assert!(!conn.read_pending());
+    }
+
+    #[test]
+    fn legacy_ping() {
+        let (mut conn, _, mut server) = open();
+        establish_legacy(&mut conn, &mut server);
+        let ping = legacy::PacketPing {
+            id: "ping-1".to_owned(),
+            peer_time: 1000,
+        };
+        server_send(
+            &mut server,
+            legacy::to_packet(PacketType::PacketPing, &ping).unwrap(),
+        );
+        receive(&mut conn);
+        let packets = server_receive(&mut server);
+        assert_eq!(packets[0].packet_type, PacketType::PacketPong);
+        assert!(!packets[0].fixed_size());
+        let pong: legacy::PacketPong = legacy::from_packet(&packets[0]).unwrap();
+        assert_eq!(pong.id, "ping-1");
+    }
+
+    #[test]
+    fn legacy_evolvable_packet() {
+        let (mut conn, _, mut server) = open();
+        establish_legacy(&mut conn, &mut server);
+        let notification = SystemNotification {
+            content: "Maintenance at noon".to_owned(),
+        };
+        server_send(
+            &mut server,
+            message_to_packet_for(&notification, legacy::PROTOCOL_VERSION).unwrap(),
+        );
+        match &receive(&mut conn)[..] {
+            [LobbyEvent::SystemNotification { content }] => {
+                assert_eq!(content, "Maintenance at noon")
+            }
+            events => panic!("Unexpected events {:?}", events),
+        }
+    }
+
+    #[test]
+    fn goodbye_not_supported() {
+        let (mut conn, _, mut server) = open();
+        establish(&mut conn, &mut server, Capabilities::empty());
+        conn.goodbye("Quit");
+        assert_eq!(conn.state, ConnState::Closed);
+        assert!(matches!(
+            &conn.drain_events()[..],
+            [LobbyEvent::Disconnected {
+                reason: DisconnectReason::Requested
+            }]
+        ));
+        assert!(server_receive(&mut server).is_empty());
+    }
+
+    #[test]
+    fn unknown_packets() {
+        let (mut conn, _, mut server) = open();
+        establish(&mut conn, &mut server, Capabilities::all());
+        let flags = PacketFlag::FixedHeader as u8
+            | PacketFlag::ShortType as u8
+            | PacketFlag::ShortSize as u8;
+        server.write_all(&[flags, 250, 2, 0, 0]).unwrap();
+        assert!(matches!(
+            receive(&mut conn)[..],
+            [LobbyEvent::UnknownPacket {
+                type_id: 250,
+                size: 2
+            }]
+        ));
+        assert_ne!(conn.state, ConnState::Closed);
+
+        server
+            .write_all(&[flags | PacketFlag::Critical as u8, 251, 0])
+            .unwrap();
+        assert!(matches!(
+            receive(&mut conn)[..],
+            [LobbyEvent::Disconnected {
+                reason: DisconnectReason::ProtocolError { .. }
+            }]
+        ));
+        assert_eq!(conn.state, ConnState::Closed);
+    }
+
+    #[test]
+    fn dispatch() {
+        let (mut conn, _, mut server) = open();
+        establish(&mut conn, &mut server, Capabilities::all());
+        // Only ever sent by the client
+        let query = "someone".to_owned();
+        server_send(
+            &mut server,
+            message_to_packet(&SearchUsers { query }).unwrap(),
+        );
+        assert!(receive(&mut conn).is_empty());
+        assert_ne!(conn.state, ConnState::Closed);
+
+        let lobby_id = "lobby".to_owned();
+        server_send(
+            &mut server,
+            message_to_packet(&LobbyJoined { lobby_id }).unwrap(),
+        );
+        assert!(matches!(
+            &receive(&mut conn)[..],
+            [LobbyEvent::LobbyJoined { lobby_id }] if lobby_id == "lobby"
+        ));
+    }
+
+    #[test]
+    fn unknown_error_code() {
+        let (mut conn, _, mut server) = open();
+        establish(&mut conn, &mut server, Capabilities::all());
+        let response = UpdateProfileResponse {
+            error_code: Some("display_name_taken".to_owned()),
+            user_profile: None,
+        };
+        server_send(&mut server, message_to_packet(&response).unwrap());
+        assert!(matches!(
+            &receive(&mut conn)[..],
+            [LobbyEvent::ProfileUpdateFailure { error_code: ErrorCode::Unknown(code) }]
+                if code == "display_name_taken"
+        ));
+        assert_ne!(conn.state, ConnState::Closed);
     }
 }
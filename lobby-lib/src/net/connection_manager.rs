@@ -1,18 +1,12 @@
-use crate::net::connection::{ConnState, Connection};
-use crate::net::packet::{packet_to_message, Packet};
+use crate::net::connection::{ConnState, Connection, ConnectionConfig};
+use crate::net::packet::Packet;
 use crate::net::socket_poller::SocketPoller;
-use crate::net::transport::tcp_socket::TcpSocket;
 use crate::net::SocketEvent;
-use crate::utils::buffer_processor::LogBufferProcessor;
-use crate::utils::byte_buffer::ByteBuffer;
 use crate::LobbyEvent;
-use bytes::{Bytes, BytesMut};
 use log::{error, info};
-use mio::net::TcpStream;
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::io::{Error, Read, Write};
-use std::net::{Shutdown, SocketAddr};
-use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+use std::time::Duration;
 use std::{io, mem};
 
 pub struct ConnectionManager {
@@ -20,25 +14,31 @@ pub struct ConnectionManager {
     connections: Vec<Connection>,
     free_tokens: VecDeque<mio::Token>,
     tokens: HashMap<SocketAddr, mio::Token>,
-    recv_buffer: Vec<u8>,
     flushables: HashSet<mio::Token>,
+    config: ConnectionConfig,
 }
 
 impl ConnectionManager {
-    pub fn new() -> Self {
+    pub fn new(config: ConnectionConfig) -> Self {
         Self {
             poller: SocketPoller::new(),
             connections: Vec::new(),
             free_tokens: VecDeque::new(),
             tokens: HashMap::new(),
-            recv_buffer: vec![0; 4096],
             flushables: HashSet::new(),
+            config,
         }
     }
 
     /// Init a connection to the given address
     pub fn connect(&mut self, addr: SocketAddr) {
-        if let Err(err) = self.new_connection(addr) {
+        let config = self.config.clone();
+        self.connect_with(addr, &config);
+    }
+
+    /// Init a connection to the given address, with specific settings
+    pub fn connect_with(&mut self, addr: SocketAddr, config: &ConnectionConfig) {
+        if let Err(err) = self.new_connection(addr, config) {
             error!("Could not create new connection: {:?}", err);
         }
     }
@@ -58,13 +58,26 @@ impl ConnectionManager {
         }
     }
 
+    /// Say goodbye to the peer, see `Connection::goodbye`
+    pub fn disconnect_gracefully(&mut self, addr: SocketAddr, reason: &str) {
+        if let Some(&token) = self.tokens.get(&addr) {
+            if let Some(conn) = self.connections.get_mut(token.0) {
+                conn.goodbye(reason);
+                if conn.state == ConnState::Closed {
+                    self.poller.deregister_connection(conn);
+                }
+            }
+        }
+    }
+
     pub fn send(&mut self, peer: SocketAddr, packet: Packet) {
         let token = if let Some(token) = self.tokens.get(&peer) {
             self.connections[token.0].send(packet);
             *token
         } else {
             info!("No connection to {} yet, initiating.", peer);
-            let mut conn = match self.new_connection(peer) {
+            let config = self.config.clone();
+            let conn = match self.new_connection(peer, &config) {
                 Ok(s) => s,
                 Err(err) => {
                     error!("Could not create new connection: {:?}", err);
@@ -77,31 +90,43 @@ impl ConnectionManager {
         self.flushables.insert(token);
     }
 
-    fn new_connection(&mut self, addr: SocketAddr) -> io::Result<&mut Connection> {
+    fn new_connection(
+        &mut self,
+        addr: SocketAddr,
+        config: &ConnectionConfig,
+    ) -> io::Result<&mut Connection> {
         if let Some(token) = self.tokens.get(&addr) {
-            let mut new_conn = Connection::open(addr, *token)?;
-            new_conn.add_buffer_processor(Box::new(LogBufferProcessor));
+            let mut new_conn = Connection::open(addr, *token, config)?;
             self.poller.register_connection(&mut new_conn)?;
-            mem::replace(&mut self.connections[token.0], new_conn);
+            let mut replaced = mem::replace(&mut self.connections[token.0], new_conn);
+            self.poller.deregister_connection(&mut replaced);
+            replaced.close();
             Ok(&mut self.connections[token.0])
         } else {
             let token = self
                 .free_tokens
                 .pop_front()
                 .unwrap_or_else(|| mio::Token(self.connections.len()));
-            let mut conn = Connection::open(addr, token)?;
-            conn.add_buffer_processor(Box::new(LogBufferProcessor));
+            let mut conn = Connection::open(addr, token, config)?;
             self.poller.register_connection(&mut conn)?;
-            self.connections.insert(token.0, conn);
+            if token.0 < self.connections.len() {
+                // Reused token of a freed connection
+                self.connections[token.0] = conn;
+            } else {
+                self.connections.push(conn);
+            }
             self.tokens.insert(addr, token);
             Ok(&mut self.connections[token.0])
         }
     }
 
-    fn close_connection(&mut self, token: mio::Token) {
+    fn close_connection(&mut self, token: mio::Token, reason: String) {
         if let Some(mut conn) = self.connections.get_mut(token.0) {
-            conn.close();
+            conn.lost(reason);
             self.poller.deregister_connection(&mut conn);
+            if conn.has_events() {
+                self.flushables.insert(token);
+            }
         }
     }
 
@@ -110,11 +135,22 @@ impl ConnectionManager {
             io::ErrorKind::ConnectionReset
             | io::ErrorKind::ConnectionRefused
             | io::ErrorKind::ConnectionAborted
-            | io::ErrorKind::BrokenPipe => true,
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::UnexpectedEof => true,
             _ => false,
         }
     }
 
+    pub fn connection(&self, addr: SocketAddr) -> Option<&Connection> {
+        let token = self.tokens.get(&addr)?;
+        self.connections.get(token.0)
+    }
+
     pub fn connect_mut(&mut self, addr: SocketAddr) -> Option<&mut Connection> {
         if let Some(token) = self.tokens.get(&addr) {
             return self.connections.get_mut(token.0);
@@ -127,12 +163,15 @@ impl ConnectionManager {
             "Received trigger for non-existent token.\
              It probably wasn't deregistered from poller before being removed.",
         );
-        if let Err(err) = conn.read(&mut self.recv_buffer) {
+        if conn.state == ConnState::Closed {
+            return;
+        }
+        if let Err(err) = conn.read() {
             match err.kind() {
                 io::ErrorKind::WouldBlock => {}
                 kind if Self::should_close(kind) => {
                     error!("Closing connection due to {:?}", err);
-                    self.close_connection(token);
+                    self.close_connection(token, err.to_string());
                     return;
                 }
                 _ => {
@@ -145,12 +184,15 @@ impl ConnectionManager {
 
     fn writable(&mut self, token: mio::Token) {
         if let Some(conn) = self.connections.get_mut(token.0) {
+            if conn.state == ConnState::Closed {
+                return;
+            }
             if let Err(err) = conn.write() {
                 match err.kind() {
                     io::ErrorKind::WouldBlock => {}
                     kind if Self::should_close(kind) => {
                         error!("Closing connection due to {:?}", err);
-                        self.close_connection(token);
+                        self.close_connection(token, err.to_string());
                         return;
                     }
                     _ => {
@@ -158,11 +200,51 @@ impl ConnectionManager {
                     }
                 }
             }
+            // Send what was queued while connecting, starting with the handshake
+            self.flushables.insert(token);
         }
     }
 
+    fn closed(&mut self, token: mio::Token) {
+        let reason = match self.connections.get_mut(token.0) {
+            Some(conn) if conn.state != ConnState::Closed => {
+                // Reading may have stopped early, handle everything the peer sent before
+                // closing, e.g. a `FatalError` or a `Goodbye`
+                while conn.read_pending() {
+                    let _ = conn.read();
+                    conn.flush();
+                }
+                match conn.socket.take_error() {
+                    Ok(Some(err)) | Err(err) => err.to_string(),
+                    Ok(None) if conn.socket.is_connected() => {
+                        "Connection closed by peer".to_owned()
+                    }
+                    Ok(None) => "Connection failed".to_owned(),
+                }
+            }
+            _ => return,
+        };
+        error!("Closing connection: {}", reason);
+        self.close_connection(token, reason);
+    }
+
     pub fn tick(&mut self, incoming_events: &mut VecDeque<LobbyEvent>, timeout: Duration) {
+        // Connections which stopped reading with data left, see `Connection::read`
+        let pending: Vec<mio::Token> = self
+            .connections
+            .iter()
+            .filter(|conn| conn.read_pending())
+            .map(|conn| conn.token)
+            .collect();
+        let timeout = if pending.is_empty() {
+            timeout
+        } else {
+            Duration::from_secs(0)
+        };
         let triggers = self.poller.tick(timeout);
+        for token in pending {
+            self.readable(token);
+        }
         for (&token, &trigger) in triggers.iter() {
             if (trigger & SocketEvent::Readable as u8) != 0 {
                 self.readable(token);
@@ -171,7 +253,7 @@ impl ConnectionManager {
                 self.writable(token);
             }
             if (trigger & SocketEvent::Closed as u8) != 0 {
-                self.close_connection(token);
+                self.closed(token);
             }
         }
 
@@ -187,5 +269,256 @@ impl ConnectionManager {
             }
         }
         assert!(self.flushables.is_empty());
+
+        for conn in &mut self.connections {
+            if conn.check_timeouts() {
+                self.poller.deregister_connection(conn);
+                incoming_events.extend(conn.drain_events());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::connection::{ConnState, ConnectionConfig};
+    use crate::net::connection_manager::ConnectionManager;
+    use crate::net::packet::{message_to_packet, Packet, MAX_PACKET_SIZE};
+    use crate::net::packet_encoder::PacketEncoder;
+    use crate::net::packets::{FatalError, NewLobbyMessage, PacketInitResponse, PacketType};
+    use crate::net::transport::memory::{MemoryControl, MemoryTransport};
+    use crate::net::transport::Transport;
+    use crate::{CloseCode, DisconnectReason, LobbyEvent};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpListener};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Manager connected to the returned server end through a memory transport
+    fn connect() -> (
+        ConnectionManager,
+        SocketAddr,
+        MemoryControl,
+        MemoryTransport,
+    ) {
+        let (client, server) = MemoryTransport::pair();
+        let control = client.control();
+        let client = RefCell::new(Some(client));
+        let config = ConnectionConfig {
+            connector: Some(Rc::new(move |_| {
+                let client = client.borrow_mut().take().expect("Single connection");
+                Ok(Box::new(client) as Box<dyn Transport>)
+            })),
+            ..ConnectionConfig::default()
+        };
+        let mut manager = ConnectionManager::new(config);
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        manager.connect(addr);
+        (manager, addr, control, server)
+    }
+
+    fn state(manager: &mut ConnectionManager, addr: SocketAddr) -> ConnState {
+        manager.connect_mut(addr).unwrap().state
+    }
+
+    fn flush(manager: &mut ConnectionManager, addr: SocketAddr) {
+        let token = manager.tokens[&addr];
+        manager.flushables.insert(token);
+        manager.tick(&mut VecDeque::new(), Duration::from_millis(0));
+    }
+
+    #[test]
+    fn close_on_read_errors() {
+        for &kind in &[
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::InvalidData,
+            io::ErrorKind::UnexpectedEof,
+        ] {
+            let (mut manager, addr, control, _server) = connect();
+            let token = manager.tokens[&addr];
+            flush(&mut manager, addr);
+            control.fail_read(kind);
+            manager.readable(token);
+            assert_eq!(state(&mut manager, addr), ConnState::Closed, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn keep_open_on_transient_errors() {
+        for &kind in &[io::ErrorKind::WouldBlock, io::ErrorKind::Interrupted] {
+            let (mut manager, addr, control, _server) = connect();
+            let token = manager.tokens[&addr];
+            flush(&mut manager, addr);
+            control.fail_read(kind);
+            manager.readable(token);
+            control.fail_write(kind);
+            manager.send(addr, Packet::new(PacketType::FetchFriendList, vec![]));
+            flush(&mut manager, addr);
+            manager.writable(token);
+            assert_ne!(state(&mut manager, addr), ConnState::Closed, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn close_on_peer_close() {
+        let (mut manager, addr, _, mut server) = connect();
+        let token = manager.tokens[&addr];
+        flush(&mut manager, addr);
+        let mut encoder = PacketEncoder::new(1024);
+        encoder.add_packet(
+            message_to_packet(&PacketInitResponse {
+                protocol_version: crate::PROTOCOL_VERSION,
+                capabilities: 0,
+            })
+            .unwrap(),
+        );
+        while let Some(buffer) = encoder.next_buffer() {
+            server.write_all(&buffer).unwrap();
+        }
+        server.close();
+        manager.readable(token);
+        assert_eq!(state(&mut manager, addr), ConnState::Closed);
+
+        let mut events = VecDeque::new();
+        manager.tick(&mut events, Duration::from_millis(0));
+        assert!(matches!(
+            events.pop_front(),
+            Some(LobbyEvent::ConnectionEstablished)
+        ));
+        match events.pop_front() {
+            Some(LobbyEvent::Disconnected {
+                reason: DisconnectReason::ConnectionLost { message },
+            }) => assert_eq!(message, "Connection closed by peer"),
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn close_after_pending_read() {
+        let (mut manager, addr, _, mut server) = connect();
+        let token = manager.tokens[&addr];
+        flush(&mut manager, addr);
+        let mut encoder = PacketEncoder::new(8 * 1024);
+        encoder.add_packet(
+            message_to_packet(&PacketInitResponse {
+                protocol_version: crate::PROTOCOL_VERSION,
+                capabilities: 0,
+            })
+            .unwrap(),
+        );
+        for _ in 0..40 {
+            let message = NewLobbyMessage {
+                lobby_id: "lobby-1".to_owned(),
+                profile: None,
+                content: "a".repeat(MAX_PACKET_SIZE / 32),
+            };
+            encoder.add_packet(message_to_packet(&message).unwrap());
+        }
+        let fatal = FatalError {
+            code: CloseCode::Banned as u16,
+            message: "Cheating".to_owned(),
+        };
+        encoder.add_packet(message_to_packet(&fatal).unwrap());
+        while let Some(buffer) = encoder.next_buffer() {
+            server.write_all(&buffer).unwrap();
+        }
+        server.close();
+
+        // Both triggers in the same tick, the read stopping before the end of the data
+        manager.readable(token);
+        assert!(manager.connect_mut(addr).unwrap().read_pending());
+        manager.closed(token);
+        assert_eq!(state(&mut manager, addr), ConnState::Closed);
+
+        let mut events = VecDeque::new();
+        manager.tick(&mut events, Duration::from_millis(0));
+        assert_eq!(events.len(), 42);
+        match events.pop_back() {
+            Some(LobbyEvent::Disconnected {
+                reason: DisconnectReason::Banned { message },
+            }) => assert_eq!(message, "Cheating"),
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn peer_close_during_handshake() {
+        let (mut manager, addr, _, mut server) = connect();
+        let token = manager.tokens[&addr];
+        flush(&mut manager, addr);
+        server.close();
+        manager.readable(token);
+        assert_eq!(state(&mut manager, addr), ConnState::Closed);
+
+        // Never established, so reported as a failed attempt rather than a disconnection
+        let mut events = VecDeque::new();
+        manager.tick(&mut events, Duration::from_millis(0));
+        match events.pop_front() {
+            Some(LobbyEvent::ConnectFailed { reason }) => {
+                assert_eq!(reason, "Connection closed by peer")
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn refused_connect() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut manager = ConnectionManager::new(ConnectionConfig::default());
+        manager.connect(addr);
+        let mut events = VecDeque::new();
+        for _ in 0..100 {
+            manager.tick(&mut events, Duration::from_millis(10));
+            if state(&mut manager, addr) == ConnState::Closed {
+                break;
+            }
+        }
+        let conn = manager.connect_mut(addr).unwrap();
+        assert_eq!(conn.state, ConnState::Closed);
+        assert!(conn.connected_time.is_none());
+        let reason = conn.close_reason.clone().unwrap();
+        assert!(reason.contains("refused"), "{}", reason);
+        // Never established, so not reported as a disconnection
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn close_replaced_connection() {
+        let (first, first_server) = MemoryTransport::pair();
+        let (second, second_server) = MemoryTransport::pair();
+        let clients = RefCell::new(vec![second, first]);
+        let config = ConnectionConfig {
+            connector: Some(Rc::new(move |_| {
+                let client = clients.borrow_mut().pop().expect("Two connections");
+                Ok(Box::new(client) as Box<dyn Transport>)
+            })),
+            ..ConnectionConfig::default()
+        };
+        let mut manager = ConnectionManager::new(config);
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        manager.connect(addr);
+        manager.connect(addr);
+        assert!(first_server.is_peer_closed());
+        assert!(!second_server.is_peer_closed());
+    }
+
+    #[test]
+    fn close_on_broken_pipe() {
+        let (mut manager, addr, control, _server) = connect();
+        let token = manager.tokens[&addr];
+        // The socket is full, then the peer goes away
+        control.fail_write(io::ErrorKind::WouldBlock);
+        flush(&mut manager, addr);
+        control.fail_write(io::ErrorKind::BrokenPipe);
+        manager.writable(token);
+        assert_eq!(state(&mut manager, addr), ConnState::Closed);
     }
 }
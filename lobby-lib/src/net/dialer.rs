@@ -0,0 +1,209 @@
+use crate::net::connection::{ConnState, ConnectionConfig};
+use crate::net::connection_manager::ConnectionManager;
+use crate::net::resolver::{PendingResolution, Resolver};
+use log::debug;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Delay before racing the next address while an attempt is still in progress (RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+pub enum DialOutcome {
+    Connected(SocketAddr),
+    Failed(String),
+}
+
+/// Resolves the server host and connects to one of its addresses, happy eyeballs style:
+/// addresses are tried alternating between IPv6 and IPv4, starting a new attempt
+/// every `CONNECTION_ATTEMPT_DELAY` (or right away if the previous one failed).
+/// The first attempt to connect wins, the other ones are closed.
+pub struct Dialer {
+    host: String,
+    port: u16,
+    resolver: Arc<dyn Resolver>,
+    config: ConnectionConfig,
+    dialing: bool,
+    resolution: Option<PendingResolution>,
+    candidates: VecDeque<SocketAddr>,
+    attempts: Vec<SocketAddr>,
+    last_attempt: Option<Instant>,
+    /// Why the last failed attempt failed
+    last_error: Option<String>,
+}
+
+impl Dialer {
+    pub fn new(
+        host: String,
+        port: u16,
+        resolver: Arc<dyn Resolver>,
+        config: ConnectionConfig,
+    ) -> Self {
+        Self {
+            host,
+            port,
+            resolver,
+            config,
+            dialing: false,
+            resolution: None,
+            candidates: VecDeque::new(),
+            attempts: Vec::new(),
+            last_attempt: None,
+            last_error: None,
+        }
+    }
+
+    pub fn is_dialing(&self) -> bool {
+        self.dialing
+    }
+
+    /// Start over, resolving the host again
+    pub fn start(&mut self, manager: &mut ConnectionManager) {
+        self.cancel(manager);
+        self.dialing = true;
+        match self.host.parse::<IpAddr>() {
+            Ok(ip) => self.candidates.push_back(SocketAddr::new(ip, self.port)),
+            Err(_) => {
+                debug!("Resolving {}", self.host);
+                self.resolution = Some(PendingResolution::start(
+                    self.resolver.clone(),
+                    &self.host,
+                    self.port,
+                ));
+            }
+        }
+    }
+
+    /// Close the attempts in progress
+    pub fn cancel(&mut self, manager: &mut ConnectionManager) {
+        for addr in self.attempts.drain(..) {
+            manager.disconnect(addr, true);
+        }
+        self.dialing = false;
+        self.resolution = None;
+        self.candidates.clear();
+        self.last_attempt = None;
+        self.last_error = None;
+    }
+
+    /// Make progress, returns the outcome once done
+    pub fn poll(&mut self, manager: &mut ConnectionManager) -> Option<DialOutcome> {
+        if !self.dialing {
+            return None;
+        }
+        if let Some(resolution) = &self.resolution {
+            match resolution.poll() {
+                Some(Ok(addrs)) => {
+                    debug!("Resolved {} to {:?}", self.host, addrs);
+                    self.resolution = None;
+                    self.candidates = interleave(addrs);
+                }
+                Some(Err(err)) => {
+                    self.cancel(manager);
+                    return Some(DialOutcome::Failed(format!(
+                        "Could not resolve {}: {}",
+                        self.host, err
+                    )));
+                }
+                None => return None,
+            }
+        }
+
+        let mut i = 0;
+        while i < self.attempts.len() {
+            let addr = self.attempts[i];
+            match manager.connect_mut(addr) {
+                Some(conn) if conn.state == ConnState::Closed => {
+                    self.last_error = conn.close_reason.clone();
+                }
+                Some(conn) if conn.socket.is_connected() => {
+                    debug!("Connected to {}", addr);
+                    self.attempts.remove(i);
+                    self.cancel(manager);
+                    return Some(DialOutcome::Connected(addr));
+                }
+                Some(_) => {
+                    i += 1;
+                    continue;
+                }
+                None => {}
+            }
+            debug!("Connection attempt to {} failed", addr);
+            self.attempts.remove(i);
+            manager.disconnect(addr, true);
+        }
+
+        let attempt_due = match self.last_attempt {
+            Some(last_attempt) => {
+                self.attempts.is_empty() || last_attempt.elapsed() >= CONNECTION_ATTEMPT_DELAY
+            }
+            None => true,
+        };
+        if attempt_due {
+            if let Some(addr) = self.candidates.pop_front() {
+                debug!("Connecting to {}", addr);
+                manager.connect_with(addr, &self.config);
+                self.attempts.push(addr);
+                self.last_attempt = Some(Instant::now());
+            }
+        }
+
+        if self.attempts.is_empty() && self.candidates.is_empty() {
+            let mut message = format!("Could not connect to {}:{}", self.host, self.port);
+            if let Some(err) = &self.last_error {
+                message = format!("{}: {}", message, err);
+            }
+            self.cancel(manager);
+            return Some(DialOutcome::Failed(message));
+        }
+        None
+    }
+}
+
+/// Alternate address families, starting with the family of the first address
+fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_is_ipv6 = addrs.first().is_some_and(|addr| addr.is_ipv6());
+    let mut preferred = VecDeque::new();
+    let mut others = VecDeque::new();
+    for addr in addrs {
+        if preferred.contains(&addr) || others.contains(&addr) {
+            continue;
+        }
+        if addr.is_ipv6() == first_is_ipv6 {
+            preferred.push_back(addr);
+        } else {
+            others.push_back(addr);
+        }
+    }
+    let mut interleaved = VecDeque::with_capacity(preferred.len() + others.len());
+    while !preferred.is_empty() || !others.is_empty() {
+        interleaved.extend(preferred.pop_front());
+        interleaved.extend(others.pop_front());
+    }
+    interleaved
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::dialer::interleave;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn interleave_families() {
+        let addrs: Vec<SocketAddr> = vec![
+            "[::1]:9000".parse().unwrap(),
+            "[::2]:9000".parse().unwrap(),
+            "[::1]:9000".parse().unwrap(),
+            "[::3]:9000".parse().unwrap(),
+            "10.0.0.1:9000".parse().unwrap(),
+        ];
+        let expected: Vec<SocketAddr> = vec![
+            "[::1]:9000".parse().unwrap(),
+            "10.0.0.1:9000".parse().unwrap(),
+            "[::2]:9000".parse().unwrap(),
+            "[::3]:9000".parse().unwrap(),
+        ];
+        assert_eq!(Vec::from(interleave(addrs)), expected);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::net::connection::ConnectionConfig;
+use crate::net::connection_manager::ConnectionManager;
+use crate::net::dialer::{DialOutcome, Dialer};
+use crate::net::resolver::Resolver;
+use crate::net::transport::tls::TlsConfig;
+use crate::net::transport::websocket::WebSocketConfig;
+use log::info;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+/// Consecutive connection failures after which the next endpoint is tried
+pub const DEFAULT_FAILOVER_THRESHOLD: u32 = 3;
+
+/// A lobby server the client can connect to.
+///
+/// The url is either `host:port`, or a WebSocket url (`ws://host[:port]/path`,
+/// `wss://host[:port]/path`). The host is a name, resolved on every connection attempt,
+/// or an IP address (IPv6 addresses being enclosed in brackets).
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    url: String,
+    region: Option<String>,
+    priority: u32,
+}
+
+impl Endpoint {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            region: None,
+            priority: 0,
+        }
+    }
+
+    pub fn with_region(mut self, region: &str) -> Self {
+        self.region = Some(region.to_owned());
+        self
+    }
+
+    /// Lower values are preferred. Defaults to 0.
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn region(&self) -> Option<&str> {
+        self.region.as_deref()
+    }
+
+    /// Dialer for this endpoint, `None` if the url is invalid
+    pub(crate) fn dialer(
+        &self,
+        resolver: Arc<dyn Resolver>,
+        config: &ConnectionConfig,
+    ) -> Option<Dialer> {
+        let mut config = config.clone();
+        let (host, port) = match websocket_url(&self.url) {
+            Some((secure, rest)) => {
+                let (authority, path) = match rest.find('/') {
+                    Some(index) => rest.split_at(index),
+                    None => (rest, "/"),
+                };
+                let default_port = if secure { 443 } else { 80 };
+                let (host, port) = parse_authority(authority, Some(default_port))?;
+                if secure && config.tls.is_none() {
+                    config.tls = Some(TlsConfig::new());
+                }
+                config.websocket = Some(WebSocketConfig {
+                    host: authority.to_owned(),
+                    path: path.to_owned(),
+                });
+                (host, port)
+            }
+            None => parse_authority(&self.url, None)?,
+        };
+        if let (Some(tls), Err(_)) = (&mut config.tls, host.parse::<IpAddr>()) {
+            tls.set_default_server_name(&host);
+        }
+        Some(Dialer::new(host, port, resolver, config))
+    }
+}
+
+pub enum EndpointOutcome {
+    Connected {
+        addr: SocketAddr,
+        /// Set when connected through another endpoint than the previous time
+        endpoint_changed: Option<Endpoint>,
+    },
+    Failed {
+        message: String,
+    },
+}
+
+/// The endpoints of the lobby, ordered by preference: endpoints of the preferred region first,
+/// then by priority.
+///
+/// The first connection probes all the most preferred endpoints at once, the fastest to connect
+/// is picked. Then the same endpoint is used until it fails to connect `failover_threshold`
+/// times in a row, at which point the next endpoint is used.
+pub struct EndpointSet {
+    endpoints: Vec<(Endpoint, Dialer)>,
+    region: Option<String>,
+    current: Option<usize>,
+    connected: Option<usize>,
+    failures: u32,
+    failover_threshold: u32,
+}
+
+impl EndpointSet {
+    pub fn new(
+        mut endpoints: Vec<(Endpoint, Dialer)>,
+        region: Option<String>,
+        failover_threshold: u32,
+    ) -> Self {
+        endpoints.sort_by_key(|(endpoint, _)| rank(endpoint, region.as_deref()));
+        Self {
+            endpoints,
+            region,
+            current: None,
+            connected: None,
+            failures: 0,
+            failover_threshold,
+        }
+    }
+
+    pub fn is_dialing(&self) -> bool {
+        self.endpoints.iter().any(|(_, dialer)| dialer.is_dialing())
+    }
+
+    /// Index of the endpoint in use, `None` until probing picked one
+    pub fn current(&self) -> Option<usize> {
+        self.current
+    }
+
+    /// Start dialing the current endpoint, or probing the preferred ones if none was picked yet
+    pub fn connect(&mut self, manager: &mut ConnectionManager) {
+        self.cancel(manager);
+        match self.current {
+            Some(current) => self.endpoints[current].1.start(manager),
+            None => {
+                let region = self.region.as_deref();
+                let best = rank(&self.endpoints[0].0, region);
+                let preferred = self
+                    .endpoints
+                    .iter()
+                    .take_while(|(endpoint, _)| rank(endpoint, region) == best)
+                    .count();
+                for (_, dialer) in &mut self.endpoints[..preferred] {
+                    dialer.start(manager);
+                }
+            }
+        }
+    }
+
+    pub fn cancel(&mut self, manager: &mut ConnectionManager) {
+        for (_, dialer) in &mut self.endpoints {
+            dialer.cancel(manager);
+        }
+    }
+
+    /// Make progress, returns the outcome once done
+    pub fn poll(&mut self, manager: &mut ConnectionManager) -> Option<EndpointOutcome> {
+        let mut failure = None;
+        for i in 0..self.endpoints.len() {
+            match self.endpoints[i].1.poll(manager) {
+                Some(DialOutcome::Connected(addr)) => {
+                    self.cancel(manager);
+                    self.current = Some(i);
+                    let endpoint_changed = if self.connected != Some(i) {
+                        self.connected = Some(i);
+                        Some(self.endpoints[i].0.clone())
+                    } else {
+                        None
+                    };
+                    return Some(EndpointOutcome::Connected {
+                        addr,
+                        endpoint_changed,
+                    });
+                }
+                Some(DialOutcome::Failed(message)) => failure = Some((i, message)),
+                None => {}
+            }
+        }
+        match failure {
+            // Other endpoints are still being probed
+            Some(_) if self.is_dialing() => None,
+            Some((index, message)) => {
+                self.failed(index);
+                Some(EndpointOutcome::Failed { message })
+            }
+            None => None,
+        }
+    }
+
+    /// The connection through the current endpoint was established
+    pub fn established(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Connecting through the endpoint at `index` failed. While probing, the current endpoint
+    /// is only picked once the failures reach the failover threshold.
+    pub fn failed(&mut self, index: usize) {
+        self.failures += 1;
+        if self.failures >= self.failover_threshold && self.endpoints.len() > 1 {
+            let next = (index + 1) % self.endpoints.len();
+            info!(
+                "Failing over from {} to {}",
+                self.endpoints[index].0.url, self.endpoints[next].0.url
+            );
+            self.current = Some(next);
+            self.failures = 0;
+        }
+    }
+}
+
+/// Sort key of the endpoint given the preferred region, lowest is preferred
+fn rank(endpoint: &Endpoint, region: Option<&str>) -> (bool, u32) {
+    (
+        region.is_none() || endpoint.region() != region,
+        endpoint.priority,
+    )
+}
+
+/// Whether the url is secure, and the part after the scheme, for WebSocket urls
+fn websocket_url(url: &str) -> Option<(bool, &str)> {
+    if let Some(rest) = url.strip_prefix("wss://") {
+        Some((true, rest))
+    } else {
+        url.strip_prefix("ws://").map(|rest| (false, rest))
+    }
+}
+
+/// Split `host[:port]`, the host being a name, an IPv4 address or an IPv6 address in brackets
+fn parse_authority(authority: &str, default_port: Option<u16>) -> Option<(String, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        host.parse::<Ipv6Addr>().ok()?;
+        if !rest.is_empty() && !rest.starts_with(':') {
+            return None;
+        }
+        (host, rest.strip_prefix(':'))
+    } else {
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        };
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_';
+        if host.is_empty() || !host.chars().all(valid_char) {
+            return None;
+        }
+        (host, port)
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port?,
+    };
+    Some((host.to_owned(), port))
+}
@@ -0,0 +1,55 @@
+//! Protocol version 1, spoken by the servers from before the version negotiation.
+//!
+//! These servers answer the client `PacketInit` with a `PacketInit` of their own instead of
+//! a `PacketInitResponse`, and send the packets whose layout changed since in the layouts below.
+//! Evolvable packets are encoded with bincode, see `Encoding::for_version`.
+//! Once such a server answered, the connection keeps using these layouts both ways.
+
+use crate::net;
+use crate::net::packet::{Encoding, Packet};
+use crate::net::packets::PacketType;
+use serde::{Deserialize, Serialize};
+
+/// Version spoken by the servers which don't negotiate
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Handshake, sent by the server in answer to the client's.
+/// The client's `PacketInit` starts with the same two fields.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PacketInit {
+    pub protocol_version: u16,
+    pub app_version: u16,
+}
+
+/// Sent with its size, the id being a string
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PacketPing {
+    pub id: String,
+    pub peer_time: u64,
+}
+
+/// Answer to `PacketPing`, in the same layout
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PacketPong {
+    pub id: String,
+    pub peer_time: u64,
+}
+
+/// `FatalError` without close code, reported as `DisconnectReason::Other` with code 0
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FatalError {
+    pub message: String,
+}
+
+/// Packet of the given type, in the version 1 layout `message`
+pub fn to_packet<T: Serialize>(packet_type: PacketType, message: &T) -> net::Result<Packet> {
+    Ok(Packet::legacy(
+        packet_type,
+        Encoding::Bincode.serialize(message)?,
+    ))
+}
+
+/// Message in the version 1 layout `T` carried by the packet
+pub fn from_packet<'de, T: Deserialize<'de>>(packet: &'de Packet) -> net::Result<T> {
+    Encoding::Bincode.deserialize(&packet.data[..])
+}
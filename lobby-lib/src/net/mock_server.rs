@@ -0,0 +1,245 @@
+use crate::net::packet::{message_to_packet, packet_to_message, Packet};
+use crate::net::packet_decoder::PacketDecoder;
+use crate::net::packet_encoder::PacketEncoder;
+use crate::net::packets::{PacketInit, PacketInitResponse, PacketType};
+use crate::net::protocol::Capabilities;
+use crate::utils::buffer_processor::{BufferProcessor, CompressionProcessor, Direction};
+use crate::{LobbyClient, LobbyEvent};
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection, StreamOwned};
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::{Message, WebSocket};
+
+/// Minimal blocking lobby server for tests. Accepts a single client, and answers every received
+/// packet with the packets returned by the handler. The client `PacketInit` is answered with
+/// the newest protocol version and all the capabilities the client asked for, unless the handler
+/// answers it. Compression is then applied as the client expects.
+pub struct MockServer {
+    pub addr: SocketAddr,
+    received: Receiver<Packet>,
+}
+
+impl MockServer {
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: FnMut(&Packet) -> Vec<Packet> + Send + 'static,
+    {
+        Self::spawn(None, false, handler)
+    }
+
+    pub fn start_tls<F>(tls_config: Arc<ServerConfig>, handler: F) -> Self
+    where
+        F: FnMut(&Packet) -> Vec<Packet> + Send + 'static,
+    {
+        Self::spawn(Some(tls_config), false, handler)
+    }
+
+    /// Serve the client over a WebSocket, the packets being carried in binary messages
+    pub fn start_websocket<F>(handler: F) -> Self
+    where
+        F: FnMut(&Packet) -> Vec<Packet> + Send + 'static,
+    {
+        Self::spawn(None, true, handler)
+    }
+
+    fn spawn<F>(tls_config: Option<Arc<ServerConfig>>, websocket: bool, handler: F) -> Self
+    where
+        F: FnMut(&Packet) -> Vec<Packet> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind mock server");
+        let addr = listener.local_addr().unwrap();
+        let (sender, received) = channel();
+        thread::spawn(move || {
+            let (stream, _) = match listener.accept() {
+                Ok(client) => client,
+                Err(_) => return,
+            };
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            match tls_config {
+                Some(config) => {
+                    let conn = ServerConnection::new(config).unwrap();
+                    serve(StreamOwned::new(conn, stream), sender, handler);
+                }
+                None if websocket => {
+                    if let Ok(socket) = tungstenite::accept(stream) {
+                        let stream = WebSocketStream {
+                            socket,
+                            pending: Vec::new(),
+                        };
+                        serve(stream, sender, handler);
+                    }
+                }
+                None => serve(stream, sender, handler),
+            }
+        });
+        Self { addr, received }
+    }
+
+    /// Packets received by the server so far
+    pub fn received(&self) -> Vec<Packet> {
+        self.received.try_iter().collect()
+    }
+}
+
+/// Byte stream over the binary messages of a WebSocket
+struct WebSocketStream {
+    socket: WebSocket<TcpStream>,
+    pending: Vec<u8>,
+}
+
+impl Read for WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            match self.socket.read() {
+                Ok(Message::Binary(data)) => self.pending = data,
+                Ok(Message::Close(_)) | Err(_) => return Ok(0),
+                Ok(_) => {}
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket
+            .send(Message::Binary(buf.to_vec()))
+            .map_err(io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush().map_err(io::Error::other)
+    }
+}
+
+/// Server TLS config using a fresh self-signed certificate for 127.0.0.1,
+/// along with the DER encoding of that certificate.
+pub fn self_signed_tls_config() -> (Arc<ServerConfig>, Vec<u8>) {
+    let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()]).unwrap();
+    let cert_der = cert.serialize_der().unwrap();
+    let key_der = cert.serialize_private_key_der();
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![Certificate(cert_der.clone())], PrivateKey(key_der))
+        .unwrap();
+    (Arc::new(config), cert_der)
+}
+
+fn serve<S, F>(mut stream: S, sender: Sender<Packet>, mut handler: F)
+where
+    S: Read + Write,
+    F: FnMut(&Packet) -> Vec<Packet>,
+{
+    let mut decoder = PacketDecoder::new();
+    let mut compression: Option<CompressionProcessor> = None;
+    let mut buffer = vec![0; 4096];
+    loop {
+        let n = match stream.read(&mut buffer) {
+            Ok(n) if n > 0 => n,
+            _ => return,
+        };
+        let mut received = VecDeque::new();
+        let data = buffer[..n].to_vec().into();
+        match &mut compression {
+            Some(processor) => processor
+                .process(data, &mut received, Direction::In)
+                .unwrap(),
+            None => received.push_back(data),
+        }
+        for data in received {
+            decoder.push_buffer(data);
+        }
+        while let Some(packet) = decoder.next_packet() {
+            let mut replies = handler(&packet);
+            if replies.is_empty() && packet.packet_type == PacketType::PacketInit {
+                let (reply, compressed) = negotiate(&packet);
+                replies.push(reply);
+                if compressed {
+                    let processor = CompressionProcessor::new(512);
+                    processor.handle().enable();
+                    compression = Some(processor);
+                }
+            }
+            if sender.send(packet).is_err() {
+                return;
+            }
+            if write_packets(&mut stream, &mut compression, replies).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Answer to the handshake, and whether compression was picked
+fn negotiate(init: &Packet) -> (Packet, bool) {
+    let init = packet_to_message::<PacketInit>(init).unwrap();
+    let reply = message_to_packet(&PacketInitResponse {
+        protocol_version: init.max_protocol_version.min(crate::PROTOCOL_VERSION),
+        capabilities: init.capabilities,
+    })
+    .unwrap();
+    let capabilities = Capabilities::from_bits_truncate(init.capabilities);
+    (reply, capabilities.contains(Capabilities::COMPRESSION))
+}
+
+fn write_packets<S: Write>(
+    stream: &mut S,
+    compression: &mut Option<CompressionProcessor>,
+    packets: Vec<Packet>,
+) -> io::Result<()> {
+    let mut encoder = PacketEncoder::new(8 * 1024);
+    for packet in packets {
+        encoder.add_packet(packet);
+    }
+    while let Some(buffer) = encoder.next_buffer() {
+        match compression {
+            Some(processor) => {
+                let mut output = VecDeque::new();
+                processor
+                    .process(buffer.to_vec().into(), &mut output, Direction::Out)
+                    .unwrap();
+                for buffer in output {
+                    stream.write_all(&buffer)?;
+                }
+            }
+            None => stream.write_all(&buffer)?,
+        }
+    }
+    stream.flush()
+}
+
+/// Tick the client until an event matching the predicate is received, or a few seconds passed.
+/// Returns all the events received in the meantime, the matching one being the last.
+pub fn tick_until<P>(client: &mut LobbyClient, mut predicate: P) -> Vec<LobbyEvent>
+where
+    P: FnMut(&LobbyEvent) -> bool,
+{
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut received = Vec::new();
+    let mut events = Vec::with_capacity(64);
+    while Instant::now() < deadline {
+        client.tick(Duration::from_millis(10));
+        client.poll_events(&mut events);
+        for event in events.drain(..) {
+            let found = predicate(&event);
+            received.push(event);
+            if found {
+                return received;
+            }
+        }
+    }
+    panic!("Timed out waiting for event, received: {:?}", received);
+}
@@ -1,32 +1,28 @@
-use crate::net::connection_manager::ConnectionManager;
-use crate::net::packet::{message_to_packet, Packet, PacketInfo};
-use crate::net::packet_encoder::PacketEncoder;
-use crate::net::packets::{PacketInit, PacketType};
-use crate::net::transport::tcp_socket::TcpSocket;
-use crate::utils::byte_buffer::ByteBuffer;
-use crate::LobbyEvent;
-use bincode::config::Options;
-use bytes::Bytes;
-use mio::net::TcpStream;
-use mio::{Interest, Registry, Token};
+use crate::net::packet::{Encoding, PacketInfo};
+use crate::net::packets::{PacketType, CUSTOM_PACKET_TYPES};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::io::{Read, Write};
-use std::net::{Shutdown, SocketAddr};
-use std::ops::Range;
-use std::time::{Duration, Instant};
-use std::{io, mem};
+use std::marker::PhantomData;
 
 pub mod connection;
 pub mod connection_manager;
+pub mod dialer;
+pub mod endpoint;
+pub mod legacy;
+#[cfg(test)]
+pub mod mock_server;
 pub mod packet;
 pub mod packet_decoder;
 pub mod packet_encoder;
 pub mod packets;
+pub mod protocol;
+pub mod resolver;
+pub mod socket;
 pub mod socket_poller;
 pub mod structs;
 pub mod transport;
+#[cfg(test)]
+mod wire_format;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -37,17 +33,45 @@ pub enum ErrorKind {
     Serialize(String),
     Deserialize(String),
     InvalidPacketType(PacketType),
+    /// The payload is larger than `MAX_PACKET_SIZE`
+    PacketTooLarge(usize),
+    Processing(String),
 }
 
 pub trait Message<'de>: Serialize + Deserialize<'de> {
     fn packet_type(&self) -> PacketType;
     fn packet_info(&self) -> PacketInfo;
-    fn serialize_data(&self) -> bincode::Result<Vec<u8>> {
-        bincode::config::DefaultOptions::new().serialize(&self)
+    fn encoding() -> Encoding {
+        Encoding::Bincode
     }
-    fn deserialize(buffer: &'de [u8]) -> bincode::Result<Self> {
-        bincode::config::DefaultOptions::new().deserialize(&buffer[..])
+    fn serialize_data(&self) -> Result<Vec<u8>> {
+        Self::encoding().serialize(self)
     }
+    fn deserialize(buffer: &'de [u8]) -> Result<Self> {
+        Self::encoding().deserialize(buffer)
+    }
+}
+
+/// Message defined by the application, sent in a custom packet.
+/// See `LobbyClient::send_custom` and `LobbyClient::on_custom`.
+pub trait CustomMessage: Serialize + DeserializeOwned {
+    /// Must be in `CUSTOM_PACKET_TYPES`, checked when building the calls to `send_custom`
+    /// and `on_custom`
+    const TYPE_ID: u16;
+
+    fn encoding() -> Encoding {
+        Encoding::Bincode
+    }
+}
+
+/// Compile time check of `CustomMessage::TYPE_ID`, evaluated where `VALID` is used
+pub(crate) struct CustomTypeId<T>(PhantomData<T>);
+
+impl<T: CustomMessage> CustomTypeId<T> {
+    pub(crate) const VALID: () = assert!(
+        *CUSTOM_PACKET_TYPES.start() <= T::TYPE_ID && T::TYPE_ID <= *CUSTOM_PACKET_TYPES.end(),
+        "Custom packet type not in CUSTOM_PACKET_TYPES"
+    );
 }
 
 #[repr(u8)]
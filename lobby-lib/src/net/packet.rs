@@ -1,8 +1,10 @@
 use crate::net;
-use crate::net::packets::PacketType;
+use crate::net::legacy;
+use crate::net::packets::{PacketType, CUSTOM_PACKET_TYPES};
 use crate::net::Message;
 use crate::net::{packets, ErrorKind};
 use crate::utils::byte_buffer::ByteBuffer;
+use bincode::config::Options;
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
@@ -15,7 +17,103 @@ pub enum PacketFlag {
     ShortType = 1 << 6,
     // Indicate 8 or 24 bits size
     ShortSize = 1 << 5,
-    // 5 bits left for future flags
+    // Receivers which don't know the type must disconnect instead of skipping the packet
+    Critical = 1 << 4,
+    // No size in the header, the size is given by the packet type
+    FixedSize = 1 << 3,
+    // More fragments of the packet follow, see `MAX_FRAME_SIZE`
+    Fragment = 1 << 2,
+    // 2 bits left for future flags
+}
+
+/// Largest payload of a single frame, the size being on 24 bits.
+/// Larger packets are sent as several frames, flagged with `PacketFlag::Fragment` but the last.
+pub const MAX_FRAME_SIZE: usize = (1 << 24) - 1;
+
+/// Largest payload of a packet, fragments included
+pub const MAX_PACKET_SIZE: usize = 1 << 25;
+
+/// How the payload of a packet is encoded
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Encoding {
+    /// Compact, but the struct must have the exact same shape on both ends
+    Bincode,
+    /// Fields are tagged with their name: fields can be removed, and added with
+    /// `#[serde(default)]`, while staying compatible with the previous versions
+    MessagePack,
+    /// Bincode with fixed width integers, the payload of fixed size packets
+    FixedWidth,
+}
+
+impl Encoding {
+    pub fn serialize<T: Serialize>(self, value: &T) -> net::Result<Vec<u8>> {
+        match self {
+            Encoding::Bincode => bincode::config::DefaultOptions::new()
+                .serialize(value)
+                .map_err(|err| ErrorKind::Serialize(err.to_string()).into()),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value)
+                .map_err(|err| ErrorKind::Serialize(err.to_string()).into()),
+            Encoding::FixedWidth => bincode::config::DefaultOptions::new()
+                .with_fixint_encoding()
+                .serialize(value)
+                .map_err(|err| ErrorKind::Serialize(err.to_string()).into()),
+        }
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>>(self, buffer: &'de [u8]) -> net::Result<T> {
+        match self {
+            Encoding::Bincode => bincode::config::DefaultOptions::new()
+                .deserialize(buffer)
+                .map_err(|err| ErrorKind::Deserialize(err.to_string()).into()),
+            Encoding::MessagePack => rmp_serde::from_slice(buffer)
+                .map_err(|err| ErrorKind::Deserialize(err.to_string()).into()),
+            Encoding::FixedWidth => bincode::config::DefaultOptions::new()
+                .with_fixint_encoding()
+                .deserialize(buffer)
+                .map_err(|err| ErrorKind::Deserialize(err.to_string()).into()),
+        }
+    }
+
+    /// Encoding used with a peer speaking the given protocol version: version 1 predates
+    /// MessagePack, evolvable packets are encoded with bincode, see `net::legacy`
+    pub fn for_version(self, protocol_version: u16) -> Encoding {
+        match self {
+            Encoding::MessagePack if protocol_version == legacy::PROTOCOL_VERSION => {
+                Encoding::Bincode
+            }
+            encoding => encoding,
+        }
+    }
+}
+
+/// Types always taking `SIZE` bytes with `Encoding::FixedWidth`,
+/// the only ones allowed in fixed size packets
+pub trait FixedSize {
+    const SIZE: usize;
+}
+
+macro_rules! impl_fixed_size {
+    ($($type:ty = $size:expr),*) => {
+        $(impl FixedSize for $type {
+            const SIZE: usize = $size;
+        })*
+    };
+}
+
+impl_fixed_size!(
+    bool = 1,
+    u8 = 1,
+    i8 = 1,
+    u16 = 2,
+    i16 = 2,
+    u32 = 4,
+    i32 = 4,
+    u64 = 8,
+    i64 = 8
+);
+
+impl<T: FixedSize, const N: usize> FixedSize for [T; N] {
+    const SIZE: usize = T::SIZE * N;
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -23,30 +121,66 @@ pub struct PacketInfo {
     pub packet_type: PacketType,
     pub name: &'static str,
     pub fixed_size: Option<usize>,
+    /// See `PacketFlag::Critical`
+    pub critical: bool,
+    pub encoding: Encoding,
 }
 
 pub struct Packet {
     pub flags: u8,
     pub packet_type: PacketType,
+    /// Type id on the wire, differs from `packet_type` for custom packets only
+    pub type_id: u16,
     pub data: ByteBuffer,
 }
 
 pub fn message_to_packet<'de, T: Message<'de>>(message: &T) -> net::Result<Packet> {
-    let packet_data = Message::serialize_data(message).map_err(|err| {
+    message_to_packet_for(message, crate::PROTOCOL_VERSION)
+}
+
+/// `message_to_packet` for a peer speaking the given protocol version
+pub fn message_to_packet_for<'de, T: Message<'de>>(
+    message: &T,
+    protocol_version: u16,
+) -> net::Result<Packet> {
+    let encoding = T::encoding().for_version(protocol_version);
+    let packet_data = encoding.serialize(message).map_err(|err| {
         ErrorKind::Serialize(format!(
             "Could not serialize packet {:?} data: {:?}",
             message.packet_type(),
             err
         ))
     })?;
+    if packet_data.len() > MAX_PACKET_SIZE {
+        return Err(ErrorKind::PacketTooLarge(packet_data.len()).into());
+    }
+    let fixed_size = message.packet_info().fixed_size;
+    if fixed_size.is_some_and(|size| size != packet_data.len()) {
+        return Err(ErrorKind::Serialize(format!(
+            "Packet {:?} of {} bytes instead of {:?}",
+            message.packet_type(),
+            packet_data.len(),
+            fixed_size
+        ))
+        .into());
+    }
     Ok(Packet::new(message.packet_type(), packet_data))
 }
 
 pub fn packet_to_message<'de, T: Message<'de>>(packet: &'de Packet) -> net::Result<T> {
+    packet_to_message_for(packet, crate::PROTOCOL_VERSION)
+}
+
+/// `packet_to_message` for a packet sent by a peer speaking the given protocol version
+pub fn packet_to_message_for<'de, T: Message<'de>>(
+    packet: &'de Packet,
+    protocol_version: u16,
+) -> net::Result<T> {
     if !packets::has(packet.packet_type) {
         return Err(ErrorKind::InvalidPacketType(packet.packet_type).into());
     }
-    Message::deserialize(&packet.data[..]).map_err(|err| {
+    let encoding = T::encoding().for_version(protocol_version);
+    encoding.deserialize(&packet.data[..]).map_err(|err| {
         ErrorKind::Deserialize(format!(
             "Could not deserialize packet type {:?}: {:?}",
             packet.packet_type, err
@@ -56,31 +190,63 @@ pub fn packet_to_message<'de, T: Message<'de>>(packet: &'de Packet) -> net::Resu
 }
 
 impl Packet {
+    /// A payload which doesn't have the size of a fixed size packet type is sent with its size,
+    /// for the receiver to reject it. `message_to_packet` fails on such payloads instead.
     pub fn new(packet_type: PacketType, data: Vec<u8>) -> Self {
         let packet_info = packets::get(packet_type);
-        if let Some(fixed_size) = packet_info.fixed_size {
-            assert_eq!(
-                fixed_size,
-                data.len(),
-                "{}",
-                format!(
-                    "Attempt to create fixed size packet {:?} with wrong size",
-                    packet_type
-                )
-            );
-        }
+        let fixed_size = packet_info.fixed_size == Some(data.len());
+        Self::with_flags(packet_type, data, fixed_size, packet_info.critical)
+    }
 
+    /// Packet in the layout of protocol version 1, see `net::legacy`: always sent with its size,
+    /// these peers not knowing the flags added since
+    pub fn legacy(packet_type: PacketType, data: Vec<u8>) -> Self {
+        Self::with_flags(packet_type, data, false, false)
+    }
+
+    fn with_flags(
+        packet_type: PacketType,
+        data: Vec<u8>,
+        fixed_size: bool,
+        critical: bool,
+    ) -> Self {
         let mut flags = PacketFlag::FixedHeader as u8;
-        if data.len() < 256 {
+        if fixed_size {
+            flags |= PacketFlag::FixedSize as u8;
+        } else if data.len() < 256 {
             flags |= PacketFlag::ShortSize as u8;
         }
         if (packet_type as u16) < 256 {
             flags |= PacketFlag::ShortType as u8;
         }
+        if critical {
+            flags |= PacketFlag::Critical as u8;
+        }
 
         Self {
             flags,
             packet_type,
+            type_id: packet_type as u16,
+            data: data.into(),
+        }
+    }
+
+    /// Packet defined by the application, the type id must be in `CUSTOM_PACKET_TYPES`
+    pub fn custom(type_id: u16, data: Vec<u8>) -> Self {
+        assert!(
+            CUSTOM_PACKET_TYPES.contains(&type_id),
+            "Custom packet type {} is not in {:?}",
+            type_id,
+            CUSTOM_PACKET_TYPES
+        );
+        let mut flags = PacketFlag::FixedHeader as u8;
+        if data.len() < 256 {
+            flags |= PacketFlag::ShortSize as u8;
+        }
+        Self {
+            flags,
+            packet_type: PacketType::Custom,
+            type_id,
             data: data.into(),
         }
     }
@@ -96,4 +262,219 @@ impl Packet {
     pub fn short_size(&self) -> bool {
         (self.flags & PacketFlag::ShortSize as u8) != 0
     }
+
+    pub fn critical(&self) -> bool {
+        (self.flags & PacketFlag::Critical as u8) != 0
+    }
+
+    pub fn fixed_size(&self) -> bool {
+        (self.flags & PacketFlag::FixedSize as u8) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::legacy;
+    use crate::net::packet::{
+        message_to_packet, message_to_packet_for, packet_to_message, packet_to_message_for,
+        Encoding, Packet, MAX_PACKET_SIZE,
+    };
+    use crate::net::packets::{LobbyMemberUpdate, PacketType, SearchUsers, SystemNotification};
+    use crate::net::structs::{LobbyMember, LobbyRole, UserProfile};
+    use crate::net::ErrorKind;
+    use serde::{Deserialize, Serialize};
+
+    /// The packets as declared by protocol version 1
+    mod v1 {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        pub struct UserProfile {
+            pub user_tag: String,
+            pub display_name: String,
+            pub avatar_url: Option<String>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        pub enum LobbyRole {
+            Leader,
+            Member,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        pub struct LobbyMember {
+            pub user_profile: UserProfile,
+            pub role: LobbyRole,
+            pub is_online: bool,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        pub struct SystemNotification {
+            pub content: String,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        pub struct LobbyMemberUpdate {
+            pub lobby_id: String,
+            pub members: Vec<LobbyMember>,
+        }
+    }
+
+    fn v1_notification() -> v1::SystemNotification {
+        v1::SystemNotification {
+            content: "Maintenance at noon".to_owned(),
+        }
+    }
+
+    fn v1_member_update() -> v1::LobbyMemberUpdate {
+        v1::LobbyMemberUpdate {
+            lobby_id: "lobby-1".to_owned(),
+            members: vec![v1::LobbyMember {
+                user_profile: v1::UserProfile {
+                    user_tag: "ann#1".to_owned(),
+                    display_name: "Ann".to_owned(),
+                    avatar_url: Some("https://avatars/ann".to_owned()),
+                },
+                role: v1::LobbyRole::Leader,
+                is_online: true,
+            }],
+        }
+    }
+
+    fn notification() -> SystemNotification {
+        SystemNotification {
+            content: "Maintenance at noon".to_owned(),
+        }
+    }
+
+    fn member_update() -> LobbyMemberUpdate {
+        LobbyMemberUpdate {
+            lobby_id: "lobby-1".to_owned(),
+            members: vec![LobbyMember {
+                user_profile: UserProfile {
+                    user_tag: "ann#1".to_owned(),
+                    display_name: "Ann".to_owned(),
+                    avatar_url: Some("https://avatars/ann".to_owned()),
+                },
+                role: LobbyRole::Leader,
+                is_online: true,
+            }],
+        }
+    }
+
+    fn assert_member_update(msg: &LobbyMemberUpdate) {
+        assert_eq!(msg.lobby_id, "lobby-1");
+        let member = &msg.members[0];
+        assert_eq!(member.user_profile.user_tag, "ann#1");
+        assert_eq!(member.user_profile.display_name, "Ann");
+        assert_eq!(
+            member.user_profile.avatar_url.as_deref(),
+            Some("https://avatars/ann")
+        );
+        assert!(matches!(member.role, LobbyRole::Leader));
+        assert!(member.is_online);
+    }
+
+    #[test]
+    fn version_1_payload_into_packet() {
+        let version = legacy::PROTOCOL_VERSION;
+        let data = Encoding::Bincode.serialize(&v1_notification()).unwrap();
+        let packet = Packet::new(PacketType::SystemNotification, data);
+        let msg = packet_to_message_for::<SystemNotification>(&packet, version).unwrap();
+        assert_eq!(msg.content, "Maintenance at noon");
+
+        let data = Encoding::Bincode.serialize(&v1_member_update()).unwrap();
+        let packet = Packet::new(PacketType::LobbyMemberUpdate, data);
+        let msg = packet_to_message_for::<LobbyMemberUpdate>(&packet, version).unwrap();
+        assert_member_update(&msg);
+    }
+
+    #[test]
+    fn packet_into_version_1_payload() {
+        let version = legacy::PROTOCOL_VERSION;
+        let packet = message_to_packet_for(&notification(), version).unwrap();
+        let decoded: v1::SystemNotification = Encoding::Bincode.deserialize(&packet.data).unwrap();
+        assert_eq!(decoded, v1_notification());
+
+        let packet = message_to_packet_for(&member_update(), version).unwrap();
+        let decoded: v1::LobbyMemberUpdate = Encoding::Bincode.deserialize(&packet.data).unwrap();
+        assert_eq!(decoded, v1_member_update());
+    }
+
+    #[test]
+    fn evolvable_payloads() {
+        // Version 1 shapes, encoded as evolvable packets
+        let data = Encoding::MessagePack
+            .serialize(&v1_member_update())
+            .unwrap();
+        let packet = Packet::new(PacketType::LobbyMemberUpdate, data);
+        assert_member_update(&packet_to_message(&packet).unwrap());
+
+        let packet = message_to_packet(&member_update()).unwrap();
+        let decoded: v1::LobbyMemberUpdate =
+            Encoding::MessagePack.deserialize(&packet.data).unwrap();
+        assert_eq!(decoded, v1_member_update());
+    }
+
+    /// `SystemNotification` as a newer version could declare it
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct NewerNotification {
+        content: String,
+        #[serde(default)]
+        level: u8,
+    }
+
+    #[test]
+    fn evolvable_packet_with_new_field() {
+        // Sent by a newer server
+        let newer = NewerNotification {
+            content: "Maintenance at noon".to_owned(),
+            level: 2,
+        };
+        let data = Encoding::MessagePack.serialize(&newer).unwrap();
+        let packet = Packet::new(PacketType::SystemNotification, data);
+        let msg = packet_to_message::<SystemNotification>(&packet).unwrap();
+        assert_eq!(msg.content, "Maintenance at noon");
+
+        // Read by a newer client
+        let packet = message_to_packet(&notification()).unwrap();
+        let decoded: NewerNotification = Encoding::MessagePack.deserialize(&packet.data).unwrap();
+        assert_eq!(
+            decoded,
+            NewerNotification {
+                content: "Maintenance at noon".to_owned(),
+                level: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn bincode_is_not_evolvable() {
+        let data = Encoding::Bincode.serialize(&v1_notification()).unwrap();
+        assert!(Encoding::Bincode
+            .deserialize::<NewerNotification>(&data)
+            .is_err());
+    }
+
+    #[test]
+    fn fixed_size_mismatch() {
+        let packet = Packet::new(PacketType::PacketPing, vec![0; 3]);
+        assert!(!packet.fixed_size());
+        assert!(Packet::new(PacketType::PacketPing, vec![0; 12]).fixed_size());
+        // Same size as a fixed size packet, but in the version 1 layout
+        assert!(!Packet::legacy(PacketType::PacketPing, vec![0; 12]).fixed_size());
+    }
+
+    #[test]
+    fn too_large_message() {
+        let msg = SearchUsers {
+            query: "a".repeat(MAX_PACKET_SIZE),
+        };
+        match message_to_packet(&msg) {
+            Err(err) => {
+                assert!(matches!(*err, ErrorKind::PacketTooLarge(size) if size > MAX_PACKET_SIZE))
+            }
+            Ok(_) => panic!("Packet larger than the max"),
+        }
+    }
 }
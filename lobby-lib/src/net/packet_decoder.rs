@@ -1,38 +1,101 @@
-use crate::net::packet::{Packet, PacketFlag};
+use crate::net::packet::{Packet, PacketFlag, MAX_FRAME_SIZE, MAX_PACKET_SIZE};
 use crate::net::packets;
-use crate::net::packets::PacketType;
-use crate::utils::byte_buffer::ByteBuffer;
+use crate::net::packets::{PacketType, CUSTOM_PACKET_TYPES};
+use crate::utils::byte_buffer::{ByteBuffer, ReadBuffer};
 use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use bytes::buf::BufExt;
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::BytesMut;
 use log::debug;
 use num_traits::FromPrimitive;
 use std::collections::VecDeque;
+use std::io;
 use std::io::Write;
 
+/// Room made for each read from the socket
+pub const READ_SIZE: usize = 4 * 1024;
+
+/// Packet of a type this version doesn't know, skipped by the decoder
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct UnknownPacket {
+    pub type_id: u16,
+    /// 0 for fixed size packets, their size is unknown
+    pub size: usize,
+    /// The stream can't be processed any further, see `PacketFlag::Critical`
+    pub critical: bool,
+}
+
+pub enum Frame {
+    Packet(Packet),
+    Unknown(UnknownPacket),
+    /// The stream is invalid, the decoder is halted
+    Malformed(String),
+}
+
+/// Fragments received so far of a packet larger than `MAX_FRAME_SIZE`
+struct Fragments {
+    type_id: u16,
+    data: BytesMut,
+}
+
 pub struct PacketDecoder {
-    stream: BytesMut,
+    stream: ReadBuffer,
+    fragments: Option<Fragments>,
+    /// Set after an unknown critical packet, nothing is decoded anymore
+    halted: bool,
 }
 
 impl PacketDecoder {
     pub fn new() -> Self {
         Self {
-            stream: BytesMut::with_capacity(8 * 1024),
+            stream: ReadBuffer::with_capacity(8 * 1024),
+            fragments: None,
+            halted: false,
         }
     }
 
     pub fn push_buffer(&mut self, buffer: ByteBuffer) {
-        self.stream.put(&buffer[..]);
+        self.stream.extend_from_slice(&buffer[..]);
     }
 
+    /// Read straight into the stream, when no buffer processor needs to see the data first.
+    /// Returns the number of bytes read.
+    pub fn read_from<F>(&mut self, read: F) -> io::Result<usize>
+    where
+        F: FnOnce(&mut [u8]) -> io::Result<usize>,
+    {
+        self.stream.read_from(READ_SIZE, read)
+    }
+
+    /// Bytes received but not decoded yet
+    pub fn buffered(&self) -> usize {
+        self.stream.len()
+    }
+
+    /// Next packet, skipping the ones of unknown types
     pub fn next_packet(&mut self) -> Option<Packet> {
-        if self.stream.remaining() < 1 {
+        loop {
+            match self.next_frame()? {
+                Frame::Packet(packet) => return Some(packet),
+                Frame::Unknown(unknown) => debug!("Skipped {:?}", unknown),
+                Frame::Malformed(reason) => {
+                    debug!("Malformed stream: {}", reason);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Next packet, or the type and size of the next packet if its type is unknown.
+    /// Unknown fixed size packets can't be skipped, they halt the decoder like critical ones.
+    /// Fragmented packets are returned once all their fragments were received.
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        if self.halted || self.stream.is_empty() {
             return None;
         }
 
         let flags = self.stream[0];
         if flags & PacketFlag::FixedHeader as u8 == 0 {
-            panic!("Internal stream in invalid state. Aborting.")
+            return Some(self.malformed(format!("Invalid packet flags {:#010b}", flags)));
         }
 
         // Header
@@ -43,13 +106,16 @@ impl PacketDecoder {
             header_size += 2;
         };
 
-        if flags & PacketFlag::ShortSize as u8 != 0 {
-            header_size += 1;
-        } else {
-            header_size += 3;
-        };
+        let fixed_size = flags & PacketFlag::FixedSize as u8 != 0;
+        if !fixed_size {
+            if flags & PacketFlag::ShortSize as u8 != 0 {
+                header_size += 1;
+            } else {
+                header_size += 3;
+            };
+        }
 
-        if self.stream.remaining() < header_size {
+        if self.stream.len() < header_size {
             return None;
         }
 
@@ -67,14 +133,28 @@ impl PacketDecoder {
             offset += 2;
         };
 
-        let packet_type = PacketType::from_u16(packet_type)
-            .expect(format!("Unknown packet type {}", packet_type).as_str());
-
-        let packet_info = packets::get(packet_type);
+        let type_id = packet_type;
+        let packet_type = if CUSTOM_PACKET_TYPES.contains(&type_id) {
+            Some(PacketType::Custom)
+        } else {
+            PacketType::from_u16(type_id).filter(|&ty| packets::has(ty))
+        };
 
         let data_size;
-        if let Some(fixed_size) = packet_info.fixed_size {
-            data_size = fixed_size;
+        if fixed_size {
+            let packet_info = packet_type.filter(|&ty| packets::has(ty)).map(packets::get);
+            match packet_info.and_then(|info| info.fixed_size) {
+                Some(size) => data_size = size,
+                None => {
+                    // Where the packet ends is unknown
+                    self.halted = true;
+                    return Some(Frame::Unknown(UnknownPacket {
+                        type_id,
+                        size: 0,
+                        critical: true,
+                    }));
+                }
+            }
         } else {
             if flags & PacketFlag::ShortSize as u8 != 0 {
                 data_size = header[offset] as usize;
@@ -86,27 +166,89 @@ impl PacketDecoder {
         }
 
         // Done
-        if self.stream.remaining() < header_size + data_size {
+        if self.stream.len() < header_size + data_size {
             return None;
         }
 
         let header = self.stream.split_to(header_size);
-        let data = self.stream.split_to(data_size);
+        let mut data = self.stream.split_to(data_size);
+
+        let fragment = flags & PacketFlag::Fragment as u8 != 0;
+        let mut flags = flags;
+        if let Some(mut fragments) = self.fragments.take() {
+            if fragments.type_id != type_id {
+                return Some(self.malformed(format!(
+                    "Packet type {} interleaved with the fragments of packet type {}",
+                    type_id, fragments.type_id
+                )));
+            }
+            if fragments.data.len() + data_size > MAX_PACKET_SIZE {
+                return Some(self.malformed(format!(
+                    "Fragmented packet type {} larger than {} bytes",
+                    type_id, MAX_PACKET_SIZE
+                )));
+            }
+            fragments.data.unsplit(data);
+            data = fragments.data;
+            // The size of the reassembled packet doesn't fit in a byte
+            flags &= !(PacketFlag::ShortSize as u8);
+        }
+        if fragment {
+            // Only the last fragment is smaller, which also bounds the number of fragments
+            if fixed_size || data_size != MAX_FRAME_SIZE {
+                return Some(self.malformed(format!(
+                    "Invalid fragment of {} bytes for packet type {}",
+                    data_size, type_id
+                )));
+            }
+            self.fragments = Some(Fragments { type_id, data });
+            return self.next_frame();
+        }
+        let data_size = data.len();
+
+        let packet_type = match packet_type {
+            Some(packet_type) => packet_type,
+            None => {
+                let critical = flags & PacketFlag::Critical as u8 != 0;
+                self.halted = critical;
+                return Some(Frame::Unknown(UnknownPacket {
+                    type_id,
+                    size: data_size,
+                    critical,
+                }));
+            }
+        };
 
         debug!(
             "Decoded new packet. Header: {:?} (Flags: {}, Type: {:?}, Data Size: {}) Data: {:?}",
             header, flags, packet_type, data_size, data
         );
-        Some(Packet::new(packet_type, data.to_vec()))
+        // Keep the flags as received, the payload is validated when deserialized.
+        // The payload isn't copied, it shares the memory of the stream.
+        Some(Frame::Packet(Packet {
+            flags,
+            packet_type,
+            type_id,
+            data: data.freeze().into(),
+        }))
+    }
+
+    fn malformed(&mut self, reason: String) -> Frame {
+        self.halted = true;
+        self.fragments = None;
+        Frame::Malformed(reason)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::net::packet::{Packet, PacketFlag};
-    use crate::net::packet_decoder::PacketDecoder;
+    use crate::net::packet::{
+        message_to_packet, packet_to_message, Packet, PacketFlag, MAX_FRAME_SIZE,
+    };
+    use crate::net::packet_decoder::{Frame, PacketDecoder, UnknownPacket};
     use crate::net::packet_encoder::PacketEncoder;
-    use crate::net::packets::PacketType;
+    use crate::net::packets::{PacketPing, PacketPong, PacketType};
+    use std::io;
 
     #[test]
     fn single_packet() {
@@ -126,6 +268,38 @@ mod tests {
         assert!(decoder.next_packet().is_none());
     }
 
+    #[test]
+    fn read_from() {
+        let mut encoder = PacketEncoder::new(256);
+        encoder.add_packet(Packet::new(PacketType::PacketInit, vec![1; 25]));
+        encoder.add_packet(Packet::new(PacketType::PacketInit, vec![2; 75]));
+        let buffer = encoder.next_buffer().unwrap();
+
+        let mut decoder = PacketDecoder::new();
+        let n = decoder
+            .read_from(|buf| {
+                buf[..buffer.len()].copy_from_slice(&buffer);
+                Ok(buffer.len())
+            })
+            .unwrap();
+        assert_eq!(n, buffer.len());
+        assert_eq!(decoder.buffered(), buffer.len());
+        let first = decoder.next_packet().unwrap();
+        let second = decoder.next_packet().unwrap();
+        assert_eq!(&first.data[..], &[1; 25]);
+        assert_eq!(&second.data[..], &vec![2; 75][..]);
+        // Both payloads point into the stream
+        assert_eq!(
+            first.data.as_ptr() as usize + 25 + 3,
+            second.data.as_ptr() as usize
+        );
+
+        // Nothing is kept of a failed read
+        let err = decoder.read_from(|_| Err(io::ErrorKind::WouldBlock.into()));
+        assert!(err.is_err());
+        assert!(decoder.next_packet().is_none());
+    }
+
     #[test]
     fn multiple_packets() {
         let mut encoder = PacketEncoder::new(256);
@@ -150,4 +324,242 @@ mod tests {
 
         assert!(decoder.next_packet().is_none());
     }
+
+    #[test]
+    fn unknown_packets() {
+        let flags = PacketFlag::FixedHeader as u8;
+        let mut stream = vec![
+            // Unknown short type 250 with 3 bytes of data
+            flags | PacketFlag::ShortType as u8 | PacketFlag::ShortSize as u8,
+            250,
+            3,
+            1,
+            2,
+            3,
+            // Unknown long type 300 with 2 bytes of data
+            flags,
+            1,
+            44,
+            0,
+            0,
+            2,
+            4,
+            5,
+        ];
+        let mut encoder = PacketEncoder::new(256);
+        encoder.add_packet(Packet::new(PacketType::SearchUsers, vec![1; 4]));
+        stream.extend_from_slice(&encoder.next_buffer().unwrap());
+
+        let mut decoder = PacketDecoder::new();
+        decoder.push_buffer(stream.into());
+        let mut unknown = Vec::new();
+        let mut packets = Vec::new();
+        let mut malformed = None;
+        while let Some(frame) = decoder.next_frame() {
+            match frame {
+                Frame::Packet(packet) => packets.push(packet),
+                Frame::Unknown(packet) => unknown.push(packet),
+                Frame::Malformed(reason) => malformed = Some(reason),
+            }
+        }
+        assert_eq!(malformed, None);
+        assert_eq!(
+            unknown,
+            [
+                UnknownPacket {
+                    type_id: 250,
+                    size: 3,
+                    critical: false
+                },
+                UnknownPacket {
+                    type_id: 300,
+                    size: 2,
+                    critical: false
+                }
+            ]
+        );
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].packet_type, PacketType::SearchUsers);
+    }
+
+    #[test]
+    fn unknown_critical_packet() {
+        let flags = PacketFlag::FixedHeader as u8
+            | PacketFlag::ShortType as u8
+            | PacketFlag::ShortSize as u8
+            | PacketFlag::Critical as u8;
+        let mut stream = vec![flags, 250, 1, 0];
+        let mut encoder = PacketEncoder::new(256);
+        encoder.add_packet(Packet::new(PacketType::SearchUsers, vec![1; 4]));
+        stream.extend_from_slice(&encoder.next_buffer().unwrap());
+
+        let mut decoder = PacketDecoder::new();
+        decoder.push_buffer(stream.into());
+        assert!(matches!(
+            decoder.next_frame(),
+            Some(Frame::Unknown(UnknownPacket { critical: true, .. }))
+        ));
+        // The rest of the stream isn't trusted anymore
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn fixed_size_packets() {
+        assert_eq!(PacketPing::INFO.fixed_size, Some(4 + 8));
+        let ping = PacketPing {
+            id: 7,
+            peer_time: u64::MAX,
+        };
+        let pong = PacketPong {
+            id: 7,
+            peer_time: 0,
+        };
+        let mut encoder = PacketEncoder::new(256);
+        encoder.add_packet(message_to_packet(&ping).unwrap());
+        encoder.add_packet(Packet::new(PacketType::SearchUsers, vec![1; 4]));
+        encoder.add_packet(message_to_packet(&pong).unwrap());
+        let buffer = encoder.next_buffer().unwrap();
+        // No size byte for the ping and the pong
+        assert_eq!(buffer.len(), (2 + 12) + (3 + 4) + (2 + 12));
+        assert_eq!(
+            buffer[0],
+            PacketFlag::FixedHeader as u8
+                | PacketFlag::ShortType as u8
+                | PacketFlag::FixedSize as u8
+        );
+        assert_eq!(buffer[1], PacketType::PacketPing as u8);
+
+        let mut decoder = PacketDecoder::new();
+        // Byte by byte, no packet is decoded before its whole payload arrived
+        let mut packets = Vec::new();
+        for &byte in &buffer[..] {
+            decoder.push_buffer(vec![byte].into());
+            packets.extend(decoder.next_packet());
+        }
+        assert_eq!(packets.len(), 3);
+        assert!(packets[0].fixed_size());
+        assert!(!packets[0].short_size());
+        let decoded: PacketPing = packet_to_message(&packets[0]).unwrap();
+        assert_eq!((decoded.id, decoded.peer_time), (7, u64::MAX));
+        assert!(!packets[1].fixed_size());
+        assert_eq!(&packets[1].data[..], &[1; 4]);
+        let decoded: PacketPong = packet_to_message(&packets[2]).unwrap();
+        assert_eq!((decoded.id, decoded.peer_time), (7, 0));
+    }
+
+    #[test]
+    fn unknown_fixed_size_packet() {
+        let flags = PacketFlag::FixedHeader as u8
+            | PacketFlag::ShortType as u8
+            | PacketFlag::FixedSize as u8;
+        let mut stream = vec![flags, 250, 1, 2];
+        let mut encoder = PacketEncoder::new(256);
+        encoder.add_packet(Packet::new(PacketType::SearchUsers, vec![1; 4]));
+        stream.extend_from_slice(&encoder.next_buffer().unwrap());
+
+        let mut decoder = PacketDecoder::new();
+        decoder.push_buffer(stream.into());
+        assert!(matches!(
+            decoder.next_frame(),
+            Some(Frame::Unknown(UnknownPacket {
+                type_id: 250,
+                size: 0,
+                critical: true
+            }))
+        ));
+        // Where the next packet starts is unknown
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn custom_packet() {
+        let mut encoder = PacketEncoder::new(256);
+        encoder.add_packet(Packet::custom(0x8001, vec![1; 4]));
+        let buffer = encoder.next_buffer().unwrap();
+        // Long type, short size
+        assert_eq!(
+            buffer[0],
+            PacketFlag::FixedHeader as u8 | PacketFlag::ShortSize as u8
+        );
+        assert_eq!(&buffer[1..5], &[0x80, 0x01, 4, 1]);
+
+        let mut decoder = PacketDecoder::new();
+        decoder.push_buffer(buffer);
+        let packet = decoder.next_packet().unwrap();
+        assert_eq!(packet.packet_type, PacketType::Custom);
+        assert_eq!(packet.type_id, 0x8001);
+        assert_eq!(&packet.data[..], &[1; 4]);
+    }
+
+    #[test]
+    fn size_boundaries() {
+        for &size in &[255, 256, MAX_FRAME_SIZE, 1 << 24] {
+            let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let mut encoder = PacketEncoder::new(256);
+            encoder.add_packet(Packet::new(PacketType::SearchUsers, data.clone()));
+            encoder.add_packet(Packet::new(PacketType::SearchUsers, vec![1; 4]));
+            let mut stream = Vec::new();
+            while let Some(buffer) = encoder.next_buffer() {
+                stream.extend_from_slice(&buffer);
+            }
+
+            // Split in the middle of the first frame
+            let mut decoder = PacketDecoder::new();
+            decoder.push_buffer(stream[..size / 2].to_vec().into());
+            assert!(decoder.next_packet().is_none());
+            decoder.push_buffer(stream[size / 2..].to_vec().into());
+            let packet = decoder.next_packet().unwrap();
+            assert_eq!(packet.short_size(), size < 256);
+            assert_eq!(packet.flags & PacketFlag::Fragment as u8, 0);
+            assert_eq!(packet.data_size(), size);
+            assert!(packet.data[..] == data[..]);
+            let packet = decoder.next_packet().unwrap();
+            assert_eq!(&packet.data[..], &[1; 4]);
+            assert!(decoder.next_packet().is_none());
+        }
+    }
+
+    #[test]
+    fn malformed_fragments() {
+        let flags = PacketFlag::FixedHeader as u8 | PacketFlag::ShortType as u8;
+        let fragment = flags | PacketFlag::Fragment as u8;
+        let first_fragment = || {
+            let mut frame = vec![fragment, PacketType::SearchUsers as u8, 0xff, 0xff, 0xff];
+            frame.resize(5 + MAX_FRAME_SIZE, 0);
+            frame
+        };
+
+        // Another packet in the middle of the fragments
+        let mut stream = first_fragment();
+        stream.extend_from_slice(&[flags, PacketType::SearchUsers as u8 + 1, 0, 0, 0]);
+        let mut decoder = PacketDecoder::new();
+        decoder.push_buffer(stream.into());
+        assert!(matches!(decoder.next_frame(), Some(Frame::Malformed(_))));
+        assert!(decoder.next_frame().is_none());
+
+        // Only the last fragment can be smaller than a frame
+        let mut stream = vec![fragment | PacketFlag::ShortSize as u8, 1, 1, 0];
+        stream.extend_from_slice(&first_fragment());
+        let mut decoder = PacketDecoder::new();
+        decoder.push_buffer(stream.into());
+        assert!(matches!(decoder.next_frame(), Some(Frame::Malformed(_))));
+        assert!(decoder.next_frame().is_none());
+
+        // Too many fragments
+        let mut stream = Vec::new();
+        for _ in 0..3 {
+            stream.extend_from_slice(&first_fragment());
+        }
+        let mut decoder = PacketDecoder::new();
+        decoder.push_buffer(stream.into());
+        assert!(matches!(decoder.next_frame(), Some(Frame::Malformed(_))));
+    }
+
+    #[test]
+    fn missing_fixed_header() {
+        let mut decoder = PacketDecoder::new();
+        decoder.push_buffer(vec![PacketFlag::ShortType as u8, 1, 0].into());
+        assert!(matches!(decoder.next_frame(), Some(Frame::Malformed(_))));
+        assert!(decoder.next_frame().is_none());
+    }
 }
@@ -1,10 +1,11 @@
-use crate::net::packet::{Packet, PacketFlag};
+use crate::net::packet::{Packet, PacketFlag, MAX_FRAME_SIZE, MAX_PACKET_SIZE};
 use crate::net::packets;
+use crate::net::packets::PacketType;
 use crate::utils::byte_buffer::ByteBuffer;
-use byteorder::{BigEndian, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, BytesMut};
 use log::debug;
 use std::collections::VecDeque;
-use std::io::Write;
 
 const MAX_PACKET_HEADER_SIZE: usize = 6;
 
@@ -12,6 +13,8 @@ pub struct PacketEncoder {
     pub target_size: usize,
     pub buffers: VecDeque<ByteBuffer>,
     pub packets: VecDeque<Packet>,
+    /// Buffers are split off this one, its memory is reused once they are all dropped
+    pool: BytesMut,
 }
 
 impl PacketEncoder {
@@ -20,6 +23,7 @@ impl PacketEncoder {
             target_size,
             buffers: VecDeque::new(),
             packets: VecDeque::new(),
+            pool: BytesMut::new(),
         }
     }
 
@@ -42,7 +46,8 @@ impl PacketEncoder {
         }
 
         let mut packet_count = 0;
-        let mut result: Vec<u8> = Vec::with_capacity(target_size);
+        let result = &mut self.pool;
+        result.reserve(target_size);
         while let Some(packet) = self.packets.front() {
             if !result.is_empty()
                 && packet.data_size() + MAX_PACKET_HEADER_SIZE + result.len() > target_size
@@ -50,40 +55,26 @@ impl PacketEncoder {
                 break;
             }
             let packet = self.packets.pop_front().unwrap();
-            if !packets::has(packet.packet_type) {
+            if packet.packet_type != PacketType::Custom && !packets::has(packet.packet_type) {
                 panic!("Packet type {:?} not registered", packet.packet_type);
             }
-            let packet_info = packets::get(packet.packet_type);
-
-            let flags_offset = result.len();
-
-            // Flags
-            result.write_u8(packet.flags);
-
-            // Type
-            if packet.short_type() {
-                result.write_u8(packet.packet_type as u8);
-                result[flags_offset] |= PacketFlag::ShortType as u8;
-            } else {
-                result.write_u16::<BigEndian>(packet.packet_type as u16);
-            }
-
-            // Size
-            if let None = packet_info.fixed_size {
-                if packet.data_size() < 256 {
-                    result.write_u8(packet.data_size() as u8);
-                    result[flags_offset] |= PacketFlag::ShortSize as u8;
-                } else {
-                    result.write_u24::<BigEndian>(packet.data_size() as u32);
-                    result[flags_offset] &= !(PacketFlag::ShortSize as u8);
+            assert!(
+                packet.data_size() <= MAX_PACKET_SIZE,
+                "Packet {:?} of {} bytes is too large",
+                packet.packet_type,
+                packet.data_size()
+            );
+
+            let mut fragments = packet.data.chunks(MAX_FRAME_SIZE).peekable();
+            loop {
+                let fragment = fragments.next().unwrap_or(&[]);
+                let last = fragments.peek().is_none();
+                write_frame(result, &packet, fragment, last);
+                if last {
+                    break;
                 }
             }
 
-            // Data
-            if packet.data_size() > 0 {
-                result.write_all(&packet.data);
-            }
-
             packet_count += 1;
         }
 
@@ -94,13 +85,53 @@ impl PacketEncoder {
                 result.len()
             )
         }
-        self.buffers.push_back(result.into());
+        self.buffers.push_back(result.split().freeze().into());
+    }
+}
+
+/// Write a frame carrying the whole payload of the packet, or a fragment of it
+fn write_frame(result: &mut BytesMut, packet: &Packet, data: &[u8], last: bool) {
+    let mut header = [0; MAX_PACKET_HEADER_SIZE];
+    let mut header_size = 1;
+
+    // Flags
+    let mut flags = packet.flags;
+    if !last {
+        flags |= PacketFlag::Fragment as u8;
+    }
+
+    // Type
+    if packet.short_type() {
+        header[header_size] = packet.type_id as u8;
+        header_size += 1;
+        flags |= PacketFlag::ShortType as u8;
+    } else {
+        BigEndian::write_u16(&mut header[header_size..], packet.type_id);
+        header_size += 2;
+    }
+
+    // Size
+    if !packet.fixed_size() {
+        if data.len() < 256 {
+            header[header_size] = data.len() as u8;
+            header_size += 1;
+            flags |= PacketFlag::ShortSize as u8;
+        } else {
+            BigEndian::write_u24(&mut header[header_size..], data.len() as u32);
+            header_size += 3;
+            flags &= !(PacketFlag::ShortSize as u8);
+        }
     }
+    header[0] = flags;
+
+    // Written at once, as the buffer checks its capacity on every write
+    result.put_slice(&header[..header_size]);
+    result.put_slice(data);
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::net::packet::Packet;
+    use crate::net::packet::{Packet, PacketFlag, MAX_FRAME_SIZE, MAX_PACKET_SIZE};
     use crate::net::packet_encoder::PacketEncoder;
     use crate::net::packets::PacketType;
 
@@ -136,4 +167,56 @@ mod tests {
         assert!(buffer.is_some());
         assert_eq!(buffer.unwrap().len(), 3 + 25 + 3 + 30);
     }
+
+    #[test]
+    fn pooled_buffers() {
+        let mut encoder = PacketEncoder::new(256);
+        encoder.add_packet(Packet::new(PacketType::PacketInit, vec![1; 25]));
+        let buffer = encoder.next_buffer().unwrap();
+
+        // Still in use, not reused
+        encoder.add_packet(Packet::new(PacketType::PacketInit, vec![2; 25]));
+        let other = encoder.next_buffer().unwrap();
+        assert_ne!(other.as_ptr(), buffer.as_ptr());
+        assert_eq!(&buffer[3..], &[1; 25]);
+        drop(buffer);
+
+        let ptr = other.as_ptr();
+        drop(other);
+        encoder.add_packet(Packet::new(PacketType::PacketInit, vec![3; 25]));
+        let buffer = encoder.next_buffer().unwrap();
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert_eq!(&buffer[3..], &[3; 25]);
+    }
+
+    #[test]
+    fn fragments() {
+        let mut encoder = PacketEncoder::new(1024);
+        encoder.add_packet(Packet::new(PacketType::PacketInit, vec![1; MAX_FRAME_SIZE]));
+        let buffer = encoder.next_buffer().unwrap();
+        assert_eq!(buffer.len(), 5 + MAX_FRAME_SIZE);
+        assert_eq!(buffer[0] & PacketFlag::Fragment as u8, 0);
+        assert_eq!(&buffer[2..5], &[0xff, 0xff, 0xff]);
+
+        // 2^24 bytes, a full frame and a 1 byte one
+        encoder.add_packet(Packet::new(PacketType::PacketInit, vec![1; 1 << 24]));
+        let buffer = encoder.next_buffer().unwrap();
+        assert_eq!(buffer.len(), (5 + MAX_FRAME_SIZE) + (3 + 1));
+        assert_ne!(buffer[0] & PacketFlag::Fragment as u8, 0);
+        let last = &buffer[5 + MAX_FRAME_SIZE..];
+        assert_eq!(last[0] & PacketFlag::Fragment as u8, 0);
+        assert_ne!(last[0] & PacketFlag::ShortSize as u8, 0);
+        assert_eq!(&last[1..], &[PacketType::PacketInit as u8, 1, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "too large")]
+    fn too_large() {
+        let mut encoder = PacketEncoder::new(1024);
+        encoder.add_packet(Packet::new(
+            PacketType::PacketInit,
+            vec![0; MAX_PACKET_SIZE + 1],
+        ));
+        encoder.next_buffer();
+    }
 }
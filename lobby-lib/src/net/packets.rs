@@ -1,146 +1,303 @@
-use crate::net::packet::PacketInfo;
+use crate::net;
+use crate::net::packet::{packet_to_message_for, Encoding, FixedSize, Packet, PacketInfo};
 use crate::net::structs::*;
+use crate::net::ErrorKind;
 use log::info;
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
 
 const MAX_PACKET_TYPES: usize = 500;
 
+/// Type ids of the packets defined by the application, see `CustomMessage`
+pub const CUSTOM_PACKET_TYPES: RangeInclusive<u16> = 0x8000..=0xFFFF;
+
+/// Declare every packet, along with its type id, in one place. This generates the packet
+/// structs, the `PacketType` enum (a duplicate id fails to compile), the registry of
+/// `PacketInfo`, and `dispatch`.
+///
+/// `incoming` packets may be received by the client, and must all have a `HandlePacket`
+/// implementation on the handler given to `dispatch`. `outgoing` packets are only sent.
+///
+/// A packet can be marked `#[critical]`, see `PacketFlag::Critical`,
+/// `#[evolvable]` to be encoded with `Encoding::MessagePack` (from protocol version 2, see
+/// `Encoding::for_version`), and `#[fixed_size]` to be sent
+/// without size, see `PacketFlag::FixedSize`. The size of a fixed size packet is computed from
+/// its fields, which must all implement `FixedSize`.
 macro_rules! declare_packets {
-    ($
-        ($struct:ident {
-            $($field:ident:$type:ty)*
-        })
-    +) => {
-        $(
-            #[derive(Debug, Serialize, Deserialize)]
-            pub struct $struct {
-                $(
-                    pub $field: $type,
-                )*
-            }
+    (@critical) => { false };
+    (@critical critical $($rest:ident)*) => { true };
+    (@critical $other:ident $($rest:ident)*) => { declare_packets!(@critical $($rest)*) };
+    (@encoding) => { Encoding::Bincode };
+    (@encoding evolvable $($rest:ident)*) => { Encoding::MessagePack };
+    (@encoding fixed_size $($rest:ident)*) => { Encoding::FixedWidth };
+    (@encoding $other:ident $($rest:ident)*) => { declare_packets!(@encoding $($rest)*) };
+    (@fixed_size [] $($type:ty),*) => { None };
+    (@fixed_size [fixed_size $($rest:ident)*] $($type:ty),*) => {
+        Some(0 $(+ <$type as FixedSize>::SIZE)*)
+    };
+    (@fixed_size [$other:ident $($rest:ident)*] $($type:ty),*) => {
+        declare_packets!(@fixed_size [$($rest)*] $($type),*)
+    };
+    (@packet [$($flag:ident)*] $struct:ident {
+        $($(#[$field_attr:meta])* $field:ident:$type:ty)*
+    }) => {
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct $struct {
+            $(
+                $(#[$field_attr])*
+                pub $field: $type,
+            )*
+        }
 
-            impl $struct {
-                pub const TYPE: PacketType = PacketType::$struct;
-                pub const INFO: PacketInfo = PacketInfo {
-                    packet_type: Self::TYPE,
-                    name: stringify!($struct),
-                    fixed_size: None,
-                };
-
-                pub fn register(packets: &mut [Option<PacketInfo>; packet_count()]) {
-                    assert!((Self::TYPE as usize) < MAX_PACKET_TYPES, "Max number of packets reached");
-                    packets[Self::TYPE as usize] = Some(Self::INFO);
-                }
+        impl $struct {
+            pub const TYPE: PacketType = PacketType::$struct;
+            pub const INFO: PacketInfo = PacketInfo {
+                packet_type: Self::TYPE,
+                name: stringify!($struct),
+                fixed_size: declare_packets!(@fixed_size [$($flag)*] $($type),*),
+                critical: declare_packets!(@critical $($flag)*),
+                encoding: declare_packets!(@encoding $($flag)*),
+            };
+        }
+
+        impl crate::net::Message<'_> for $struct {
+            fn packet_type(&self) -> PacketType {
+                Self::TYPE
+            }
+            fn packet_info(&self) -> PacketInfo {
+                Self::INFO
+            }
+            fn encoding() -> Encoding {
+                Self::INFO.encoding
             }
+        }
+    };
+    (
+        incoming {
+            $($(#[$in_flag:ident])* $in_id:literal => $in_struct:ident { $($in_body:tt)* })*
+        }
+        outgoing {
+            $($(#[$out_flag:ident])* $out_id:literal => $out_struct:ident { $($out_body:tt)* })*
+        }
+    ) => {
+        $(declare_packets!(@packet [$($in_flag)*] $in_struct { $($in_body)* });)*
+        $(declare_packets!(@packet [$($out_flag)*] $out_struct { $($out_body)* });)*
+
+        #[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
+        #[repr(u16)]
+        pub enum PacketType {
+            $($in_struct = $in_id,)*
+            $($out_struct = $out_id,)*
+            /// Any custom packet, its type id being `Packet::type_id`
+            Custom = *CUSTOM_PACKET_TYPES.start(),
+        }
+
+        const PACKET_TYPES: &[PacketType] = &[
+            $(PacketType::$in_struct,)*
+            $(PacketType::$out_struct,)*
+        ];
 
-            impl crate::net::Message<'_> for $struct {
-                fn packet_type(&self) -> PacketType {
-                    Self::TYPE
-                }
-                fn packet_info(&self) -> PacketInfo {
-                    Self::INFO
-                }
+        fn init_packets(types: &mut [Option<PacketInfo>; packet_count()]) {
+            $(types[PacketType::$in_struct as usize] = Some($in_struct::INFO);)*
+            $(types[PacketType::$out_struct as usize] = Some($out_struct::INFO);)*
+        }
+
+        /// Deserialize an incoming packet sent with the given protocol version, and hand it to the
+        /// matching `HandlePacket` implementation
+        pub fn dispatch<H>(handler: &mut H, packet: &Packet, protocol_version: u16) -> net::Result<()>
+        where
+            $(H: HandlePacket<$in_struct>,)*
+        {
+            match packet.packet_type {
+                $(
+                    PacketType::$in_struct => HandlePacket::<$in_struct>::handle(
+                        handler,
+                        packet_to_message_for(packet, protocol_version)?,
+                    ),
+                )*
+                packet_type => Err(ErrorKind::InvalidPacketType(packet_type).into()),
             }
-        )+
+        }
     };
 }
 
+/// Handles the incoming packets of type `T`, see `dispatch`
+pub trait HandlePacket<T> {
+    fn handle(&mut self, msg: T) -> net::Result<()>;
+}
+
 declare_packets! {
-    FatalError {
-        message: String
-    }
-    PacketInit {
-        protocol_version: u16
-        app_version: u16
-    }
-    AuthenticationRequest {
-        email: String
-        password: String
-    }
-    AuthenticationResponse {
-        error_code: Option<String>
-        session_token: Option<String>
-        user_profile: Option<UserProfile>
-    }
-    PacketPing {
-        id: String
-        peer_time: u64
-    }
-    PacketPong {
-        id: String
-        peer_time: u64
-    }
-    AddFriendRequest {
-        user_tag: String
+    incoming {
+        0 => FatalError {
+            code: u16
+            message: String
+        }
+        3 => AuthenticationResponse {
+            error_code: Option<String>
+            session_token: Option<String>
+            user_profile: Option<UserProfile>
+        }
+        #[fixed_size]
+        4 => PacketPing {
+            id: u32
+            peer_time: u64
+        }
+        7 => AddFriendRequestResponse {
+            user_tag: String
+            error_code: Option<String>
+        }
+        9 => FriendRequestActionResponse {
+            request_id: String
+            error_code: Option<String>
+        }
+        11 => FetchPendingFriendRequestsResponse {
+            pending_as_inviter: Vec<FriendRequest>
+            pending_as_invitee: Vec<FriendRequest>
+        }
+        13 => FetchFriendListResponse {
+            friend_list: Vec<Friend>
+        }
+        15 => RemoveFriendResponse {
+            error_code: Option<String>
+        }
+        17 => NewPrivateMessage {
+            profile: UserProfile
+            content: String
+            is_self: bool
+        }
+        #[evolvable]
+        18 => SystemNotification {
+            content: String
+        }
+        20 => LobbyInvite {
+            id: String
+            inviter: UserProfile
+        }
+        22 => LobbyJoined {
+            lobby_id: String
+        }
+        #[evolvable]
+        23 => LobbyMemberUpdate {
+            lobby_id: String
+            members: Vec<LobbyMember>
+        }
+        24 => LobbyLeft {
+            lobby_id: String
+        }
+        26 => NewLobbyMessage {
+            lobby_id: String
+            profile: Option<UserProfile>
+            content: String
+        }
+        30 => DeviceCodeIssued {
+            user_code: String
+            verification_uri: String
+            expires_in: u32
+        }
+        32 => UpdateProfileResponse {
+            error_code: Option<String>
+            user_profile: Option<UserProfile>
+        }
+        34 => FetchProfileResponse {
+            user_tag: String
+            user_profile: Option<UserProfile>
+        }
+        36 => SearchUsersResponse {
+            query: String
+            results: Vec<UserProfile>
+        }
+        #[evolvable]
+        37 => ProfileUpdated {
+            user_profile: UserProfile
+        }
+        40 => Goodbye {
+            reason: String
+        }
+        41 => GoodbyeAck {}
+        #[critical]
+        42 => PacketInitResponse {
+            protocol_version: u16
+            capabilities: u32
+        }
+        43 => NewLobbyData {
+            lobby_id: String
+            profile: UserProfile
+            channel: String
+            payload: Vec<u8>
+        }
     }
-    AddFriendRequestResponse {
-        user_tag: String
-        error_code: Option<String>
-    }
-    FriendRequestAction {
-        request_id: String
-        action: FriendRequestActionChoice
-    }
-    FriendRequestActionResponse {
-        request_id: String
-        error_code: Option<String>
-    }
-    FetchPendingFriendRequests {}
-    FetchPendingFriendRequestsResponse {
-        pending_as_inviter: Vec<FriendRequest>
-        pending_as_invitee: Vec<FriendRequest>
-    }
-    FetchFriendList{}
-    FetchFriendListResponse {
-        friend_list: Vec<Friend>
-    }
-    RemoveFriend {
-        user_tag: String
-    }
-    RemoveFriendResponse {
-        error_code: Option<String>
-    }
-    SendPrivateMessage {
-        user_tag: String
-        content: String
-    }
-    NewPrivateMessage {
-        profile: UserProfile
-        content: String
-        is_self: bool
-    }
-    SystemNotification {
-        content: String
-    }
-    InviteUser {
-        user_tag: String
-    }
-    LobbyInvite {
-        id: String
-        inviter: UserProfile
-    }
-    LobbyInviteAction {
-        invite_id: String
-        action: LobbyInviteActionChoice
-    }
-    LobbyJoined {
-        lobby_id: String
-    }
-    LobbyMemberUpdate {
-        lobby_id: String
-        members: Vec<LobbyMember>
-    }
-    LobbyLeft {
-        lobby_id: String
-    }
-    SendLobbyMessage {
-        content: String
-    }
-    NewLobbyMessage {
-        lobby_id: String
-        profile: Option<UserProfile>
-        content: String
+    outgoing {
+        // The servers of protocol version 1 answer with a `PacketInit` in the layout of
+        // `legacy::PacketInit`, which is also the layout of the first two fields
+        #[critical]
+        1 => PacketInit {
+            min_protocol_version: u16
+            app_version: u16
+            max_protocol_version: u16
+            capabilities: u32
+        }
+        2 => AuthenticationRequest {
+            email: String
+            password: String
+        }
+        #[fixed_size]
+        5 => PacketPong {
+            id: u32
+            peer_time: u64
+        }
+        6 => AddFriendRequest {
+            user_tag: String
+        }
+        8 => FriendRequestAction {
+            request_id: String
+            action: FriendRequestActionChoice
+        }
+        10 => FetchPendingFriendRequests {}
+        12 => FetchFriendList {}
+        14 => RemoveFriend {
+            user_tag: String
+        }
+        16 => SendPrivateMessage {
+            user_tag: String
+            content: String
+        }
+        19 => InviteUser {
+            user_tag: String
+        }
+        21 => LobbyInviteAction {
+            invite_id: String
+            action: LobbyInviteActionChoice
+        }
+        25 => SendLobbyMessage {
+            content: String
+        }
+        27 => TokenAuthenticationRequest {
+            token: String
+        }
+        28 => TicketAuthenticationRequest {
+            platform: String
+            ticket: Vec<u8>
+        }
+        29 => DeviceCodeAuthenticationRequest {
+            client_id: String
+        }
+        31 => UpdateProfile {
+            display_name: Option<String>
+            avatar_url: Option<String>
+        }
+        33 => FetchProfile {
+            user_tag: String
+        }
+        35 => SearchUsers {
+            query: String
+        }
+        44 => SendLobbyData {
+            channel: String
+            payload: Vec<u8>
+            /// User tag of the only member to relay to, all the other members if unset
+            target: Option<String>
+        }
     }
 }
 
@@ -152,70 +309,6 @@ lazy_static! {
     };
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, FromPrimitive)]
-#[repr(u16)]
-pub enum PacketType {
-    FatalError = 0,
-    PacketInit = 1,
-    AuthenticationRequest = 2,
-    AuthenticationResponse = 3,
-    PacketPing = 4,
-    PacketPong = 5,
-    AddFriendRequest = 6,
-    AddFriendRequestResponse = 7,
-    FriendRequestAction = 8,
-    FriendRequestActionResponse = 9,
-    FetchPendingFriendRequests = 10,
-    FetchPendingFriendRequestsResponse = 11,
-    FetchFriendList = 12,
-    FetchFriendListResponse = 13,
-    RemoveFriend = 14,
-    RemoveFriendResponse = 15,
-    SendPrivateMessage = 16,
-    NewPrivateMessage = 17,
-    SystemNotification = 18,
-    InviteUser = 19,
-    LobbyInvite = 20,
-    LobbyInviteAction = 21,
-    LobbyJoined = 22,
-    LobbyMemberUpdate = 23,
-    LobbyLeft = 24,
-    SendLobbyMessage = 25,
-    NewLobbyMessage = 26,
-
-    Last,
-}
-
-fn init_packets(types: &mut [Option<PacketInfo>; packet_count()]) {
-    FatalError::register(types);
-    PacketInit::register(types);
-    AuthenticationRequest::register(types);
-    AuthenticationResponse::register(types);
-    PacketPing::register(types);
-    PacketPong::register(types);
-    AddFriendRequest::register(types);
-    AddFriendRequestResponse::register(types);
-    FriendRequestAction::register(types);
-    FriendRequestActionResponse::register(types);
-    FetchPendingFriendRequests::register(types);
-    FetchPendingFriendRequestsResponse::register(types);
-    FetchFriendList::register(types);
-    FetchFriendListResponse::register(types);
-    RemoveFriend::register(types);
-    RemoveFriendResponse::register(types);
-    SendPrivateMessage::register(types);
-    NewPrivateMessage::register(types);
-    SystemNotification::register(types);
-    InviteUser::register(types);
-    LobbyInvite::register(types);
-    LobbyInviteAction::register(types);
-    LobbyJoined::register(types);
-    LobbyMemberUpdate::register(types);
-    LobbyLeft::register(types);
-    SendLobbyMessage::register(types);
-    NewLobbyMessage::register(types);
-}
-
 pub fn init() {
     info!(
         "Initialized {} packet types",
@@ -223,15 +316,31 @@ pub fn init() {
     );
 }
 
+/// Highest packet type id, plus one
 pub const fn packet_count() -> usize {
-    PacketType::Last as usize
+    let mut count = 0;
+    let mut i = 0;
+    while i < PACKET_TYPES.len() {
+        let id = PACKET_TYPES[i] as usize;
+        if id >= count {
+            count = id + 1;
+        }
+        i += 1;
+    }
+    count
 }
 
+const _: () = assert!(
+    packet_count() <= MAX_PACKET_TYPES,
+    "Max number of packets reached"
+);
+const _: () = assert!(MAX_PACKET_TYPES <= *CUSTOM_PACKET_TYPES.start() as usize);
+
 pub fn has(packet_type: PacketType) -> bool {
     (packet_type as usize) < packet_count() && PACKET_INFOS[packet_type as usize].is_some()
 }
 
 pub fn get(packet_type: PacketType) -> PacketInfo {
     PACKET_INFOS[packet_type as usize]
-        .expect(&format!("Packet type {:?} is not registered!", packet_type))
+        .unwrap_or_else(|| panic!("Packet type {:?} is not registered!", packet_type))
 }
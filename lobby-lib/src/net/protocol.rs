@@ -0,0 +1,22 @@
+bitflags! {
+    /// Optional protocol features, advertised by the client and picked by the server
+    pub struct Capabilities: u32 {
+        /// Buffer compression of both directions after the handshake, see `CompressionProcessor`
+        const COMPRESSION = 1 << 0;
+        /// Graceful disconnection, the server acknowledging `Goodbye`
+        const GOODBYE = 1 << 1;
+    }
+}
+
+/// Protocol version and features agreed on with the server during the handshake
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NegotiatedProtocol {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl NegotiatedProtocol {
+    pub fn supports(&self, capabilities: Capabilities) -> bool {
+        self.capabilities.contains(capabilities)
+    }
+}
@@ -0,0 +1,54 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+/// Resolves a host name to the addresses to try connecting to
+pub trait Resolver: Send + Sync {
+    /// Blocking resolution, ran on a background thread
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolution through the operating system (`getaddrinfo`)
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&str, u16) -> io::Result<Vec<SocketAddr>> + Send + Sync,
+{
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self(host, port)
+    }
+}
+
+/// Resolution running in the background, so the game loop is never blocked
+pub struct PendingResolution {
+    receiver: Receiver<io::Result<Vec<SocketAddr>>>,
+}
+
+impl PendingResolution {
+    pub fn start(resolver: Arc<dyn Resolver>, host: &str, port: u16) -> Self {
+        let (sender, receiver) = channel();
+        let host = host.to_owned();
+        thread::spawn(move || {
+            let _ = sender.send(resolver.resolve(&host, port));
+        });
+        Self { receiver }
+    }
+
+    /// Result of the resolution, if done
+    pub fn poll(&self) -> Option<io::Result<Vec<SocketAddr>>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(io::Error::other("Resolver panicked"))),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::net;
+use crate::net::transport::Transport;
+use crate::utils::buffer_processor::{BufferProcessor, Direction};
+use crate::utils::byte_buffer::ByteBuffer;
+use mio::{Interest, Registry, Token};
+use std::collections::VecDeque;
+use std::{io, mem};
+
+/// A transport along with the buffer processors applied to the data going through it
+pub struct Socket {
+    transport: Box<dyn Transport>,
+    connected: bool,
+    closed: bool,
+
+    buffer_processors: Vec<Box<dyn BufferProcessor>>,
+    pub unprocessed_in: VecDeque<ByteBuffer>,
+    pub unprocessed_out: VecDeque<ByteBuffer>,
+    pub processed_in: VecDeque<ByteBuffer>,
+    pub processed_out: VecDeque<ByteBuffer>,
+}
+
+impl Socket {
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Self {
+            transport,
+            connected: false,
+            closed: false,
+            buffer_processors: Vec::new(),
+            unprocessed_in: VecDeque::new(),
+            unprocessed_out: VecDeque::new(),
+            processed_in: VecDeque::new(),
+            processed_out: VecDeque::new(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Check that the connect succeeded, `WouldBlock` if it is still in progress
+    pub fn connected(&mut self) -> io::Result<()> {
+        self.transport.connected()?;
+        self.connected = true;
+        Ok(())
+    }
+
+    pub fn take_error(&mut self) -> io::Result<Option<io::Error>> {
+        self.transport.take_error()
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.transport.read(buf)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transport.write(buf)
+    }
+
+    /// Write any data buffered by the transport itself (e.g. TLS records, WebSocket frames)
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.transport.flush()
+    }
+
+    /// Close the transport, even while the connect is still in progress
+    pub fn close(&mut self) {
+        if !self.closed {
+            self.transport.close();
+            self.closed = true;
+            self.connected = false;
+        }
+    }
+
+    pub fn add_buffer_processor(&mut self, buffer_processor: Box<dyn BufferProcessor>) {
+        self.buffer_processors.push(buffer_processor);
+    }
+
+    pub fn has_buffer_processors(&self) -> bool {
+        !self.buffer_processors.is_empty()
+    }
+
+    pub fn process_in(&mut self) -> net::Result<()> {
+        let mut buffers = mem::take(&mut self.unprocessed_in);
+        for processor in self.buffer_processors.iter_mut().rev() {
+            let mut output = VecDeque::with_capacity(buffers.len());
+            while let Some(buffer) = buffers.pop_front() {
+                processor.process(buffer, &mut output, Direction::In)?;
+            }
+            buffers = output;
+        }
+        self.processed_in.extend(buffers);
+        Ok(())
+    }
+
+    pub fn process_out(&mut self) -> net::Result<()> {
+        let mut buffers = mem::take(&mut self.unprocessed_out);
+        for processor in self.buffer_processors.iter_mut() {
+            let mut output = VecDeque::with_capacity(buffers.len());
+            while let Some(buffer) = buffers.pop_front() {
+                processor.process(buffer, &mut output, Direction::Out)?;
+            }
+            buffers = output;
+        }
+        self.processed_out.extend(buffers);
+        Ok(())
+    }
+}
+
+impl mio::event::Source for Socket {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.transport.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.transport.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.transport.deregister(registry)
+    }
+}
@@ -44,6 +44,9 @@ impl SocketPoller {
             if event.is_writable() {
                 trigger |= SocketEvent::Writable as u8;
             }
+            if event.is_error() || event.is_read_closed() {
+                trigger |= SocketEvent::Closed as u8;
+            }
 
             *triggers.entry(event.token()).or_insert(trigger) |= trigger;
         }
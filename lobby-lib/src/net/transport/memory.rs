@@ -0,0 +1,249 @@
+use crate::net::transport::Transport;
+use mio::{Interest, Registry, Token};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+/// One direction of a memory pipe
+#[derive(Default)]
+struct Pipe {
+    data: VecDeque<u8>,
+    /// Written while the pipe was paused, delivered on resume
+    in_flight: VecDeque<u8>,
+    paused: bool,
+    /// The writing end is closed, reads return EOF once the data is drained
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Behavior {
+    read_chunk: Option<usize>,
+    write_chunk: Option<usize>,
+    read_errors: VecDeque<io::ErrorKind>,
+    write_errors: VecDeque<io::ErrorKind>,
+}
+
+/// In-memory duplex transport, for deterministic tests.
+///
+/// Both ends are created with `pair`. Each end can be made to read and write in small chunks,
+/// hold the data it sends until resumed, and fail the next reads or writes with a given error,
+/// through its `MemoryControl`. Nothing is ever registered to the poller:
+/// tests drive reads and writes directly.
+pub struct MemoryTransport {
+    incoming: Rc<RefCell<Pipe>>,
+    outgoing: Rc<RefCell<Pipe>>,
+    behavior: Rc<RefCell<Behavior>>,
+}
+
+/// Handle changing the behavior of a `MemoryTransport`, usable after it was moved into a connection
+#[derive(Clone)]
+pub struct MemoryControl {
+    outgoing: Rc<RefCell<Pipe>>,
+    behavior: Rc<RefCell<Behavior>>,
+}
+
+impl MemoryTransport {
+    /// Create two connected ends
+    pub fn pair() -> (Self, Self) {
+        let a_to_b = Rc::new(RefCell::new(Pipe::default()));
+        let b_to_a = Rc::new(RefCell::new(Pipe::default()));
+        let a = Self {
+            incoming: b_to_a.clone(),
+            outgoing: a_to_b.clone(),
+            behavior: Rc::default(),
+        };
+        let b = Self {
+            incoming: a_to_b,
+            outgoing: b_to_a,
+            behavior: Rc::default(),
+        };
+        (a, b)
+    }
+
+    pub fn control(&self) -> MemoryControl {
+        MemoryControl {
+            outgoing: self.outgoing.clone(),
+            behavior: self.behavior.clone(),
+        }
+    }
+
+    /// Whether the other end was closed
+    pub fn is_peer_closed(&self) -> bool {
+        self.incoming.borrow().closed
+    }
+}
+
+impl MemoryControl {
+    /// Return at most `size` bytes per read
+    pub fn set_read_chunk(&self, size: Option<usize>) {
+        self.behavior.borrow_mut().read_chunk = size;
+    }
+
+    /// Accept at most `size` bytes per write, leading to partial writes
+    pub fn set_write_chunk(&self, size: Option<usize>) {
+        self.behavior.borrow_mut().write_chunk = size;
+    }
+
+    /// Fail the next read with the given error
+    pub fn fail_read(&self, kind: io::ErrorKind) {
+        self.behavior.borrow_mut().read_errors.push_back(kind);
+    }
+
+    /// Fail the next write with the given error
+    pub fn fail_write(&self, kind: io::ErrorKind) {
+        self.behavior.borrow_mut().write_errors.push_back(kind);
+    }
+
+    /// Hold the written data instead of delivering it to the other end
+    pub fn pause(&self) {
+        self.outgoing.borrow_mut().paused = true;
+    }
+
+    /// Deliver the held data, and stop holding
+    pub fn resume(&self) {
+        let mut pipe = self.outgoing.borrow_mut();
+        pipe.paused = false;
+        let in_flight = std::mem::take(&mut pipe.in_flight);
+        pipe.data.extend(in_flight);
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut behavior = self.behavior.borrow_mut();
+        if let Some(kind) = behavior.read_errors.pop_front() {
+            return Err(kind.into());
+        }
+        let mut pipe = self.incoming.borrow_mut();
+        if pipe.data.is_empty() {
+            return if pipe.closed && pipe.in_flight.is_empty() {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            };
+        }
+        let n = buf
+            .len()
+            .min(pipe.data.len())
+            .min(behavior.read_chunk.unwrap_or(usize::MAX));
+        for (dst, src) in buf.iter_mut().zip(pipe.data.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut behavior = self.behavior.borrow_mut();
+        if let Some(kind) = behavior.write_errors.pop_front() {
+            return Err(kind.into());
+        }
+        if self.incoming.borrow().closed || self.outgoing.borrow().closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let n = buf.len().min(behavior.write_chunk.unwrap_or(usize::MAX));
+        let mut pipe = self.outgoing.borrow_mut();
+        if pipe.paused {
+            pipe.in_flight.extend(&buf[..n]);
+        } else {
+            pipe.data.extend(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn close(&mut self) {
+        self.outgoing.borrow_mut().closed = true;
+    }
+}
+
+impl mio::event::Source for MemoryTransport {
+    fn register(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&mut self, _: &Registry) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::transport::memory::MemoryTransport;
+    use crate::net::transport::Transport;
+    use std::io;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn chunks_and_partial_writes() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        a.control().set_write_chunk(Some(3));
+        b.control().set_read_chunk(Some(2));
+        assert_eq!(a.write(b"hello").unwrap(), 3);
+
+        let mut buf = [0; 8];
+        assert_eq!(b.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"he");
+        assert_eq!(b.read(&mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"l");
+        assert_eq!(
+            b.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        let control = a.control();
+        control.pause();
+        assert_eq!(a.write(b"ping").unwrap(), 4);
+
+        let mut buf = [0; 8];
+        assert_eq!(
+            b.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        control.resume();
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+    }
+
+    #[test]
+    fn injected_errors_and_close() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        a.control().fail_write(io::ErrorKind::WouldBlock);
+        b.control().fail_read(io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            a.write(b"data").unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(a.write(b"data").unwrap(), 4);
+
+        let mut buf = [0; 8];
+        assert_eq!(
+            b.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+        a.close();
+        assert!(b.is_peer_closed());
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            b.write(b"data").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -1 +1,52 @@
+use crate::net::connection::ConnectionConfig;
+use crate::net::transport::tcp_socket::TcpSocket;
+use crate::net::transport::tls::TlsTransport;
+use crate::net::transport::websocket::WebSocketTransport;
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+pub mod memory;
 pub mod tcp_socket;
+pub mod tls;
+pub mod websocket;
+
+/// Non blocking byte stream carrying the lobby protocol.
+///
+/// Reads and writes follow the semantics of a non blocking socket (`WouldBlock`, `Ok(0)` on EOF),
+/// and `flush` writes any data buffered by the transport itself (e.g. TLS records).
+/// Transports are registered to the poller like any mio source, and can be layered:
+/// TLS and WebSocket wrap another transport.
+pub trait Transport: Read + Write + mio::event::Source {
+    /// Called on the first writable event, once the connection is established
+    fn connected(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Pending error of the underlying socket (e.g. why the connect failed)
+    fn take_error(&mut self) -> io::Result<Option<io::Error>> {
+        Ok(None)
+    }
+
+    /// Shutdown the transport, gracefully if possible
+    fn close(&mut self);
+}
+
+/// Open a transport to the given address, see `ConnectionConfig::connector`
+pub type Connector = Rc<dyn Fn(SocketAddr) -> io::Result<Box<dyn Transport>>>;
+
+/// Issue a non blocking TCP connect, wrapped in TLS and WebSocket as configured
+pub fn connect(addr: SocketAddr, config: &ConnectionConfig) -> io::Result<Box<dyn Transport>> {
+    if let Some(connector) = &config.connector {
+        return connector(addr);
+    }
+    let mut transport: Box<dyn Transport> = Box::new(TcpSocket::connect(addr)?);
+    if let Some(tls) = &config.tls {
+        transport = Box::new(TlsTransport::new(transport, tls, addr)?);
+    }
+    if let Some(websocket) = &config.websocket {
+        transport = Box::new(WebSocketTransport::new(transport, websocket)?);
+    }
+    Ok(transport)
+}
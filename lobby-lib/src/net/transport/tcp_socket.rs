@@ -1,76 +1,62 @@
-use crate::net::packet::Packet;
-use crate::net::packet_decoder::PacketDecoder;
-use crate::net::packet_encoder::PacketEncoder;
-use crate::utils::buffer_processor::{BufferProcessor, Direction};
-use crate::utils::byte_buffer::ByteBuffer;
+use crate::net::transport::Transport;
 use mio::net::TcpStream;
 use mio::{Interest, Registry, Token};
-use std::collections::VecDeque;
 use std::io;
-use std::net::Shutdown;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr};
 
 pub struct TcpSocket {
     pub stream: TcpStream,
-    connected: bool,
-
-    buffer_processors: Vec<Box<dyn BufferProcessor>>,
-    pub unprocessed_in: VecDeque<ByteBuffer>,
-    pub unprocessed_out: VecDeque<ByteBuffer>,
-    pub processed_in: VecDeque<ByteBuffer>,
-    pub processed_out: VecDeque<ByteBuffer>,
 }
 
 impl TcpSocket {
-    pub fn new(stream: TcpStream) -> Self {
-        Self {
-            stream,
-            connected: false,
-            buffer_processors: Vec::new(),
-            unprocessed_in: VecDeque::new(),
-            unprocessed_out: VecDeque::new(),
-            processed_in: VecDeque::new(),
-            processed_out: VecDeque::new(),
-        }
-    }
-
-    pub fn is_connected(&self) -> bool {
-        self.connected
+    /// Issue a non blocking connect
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            stream: TcpStream::connect(addr)?,
+        })
     }
+}
 
-    pub fn connected(&mut self) {
-        self.stream
-            .set_nodelay(true)
-            .expect("Could not set nodelay on socket");
-        self.connected = true;
+impl Read for TcpSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
     }
+}
 
-    pub fn close(&mut self) {
-        if self.connected {
-            self.stream.shutdown(Shutdown::Both);
-            self.connected = false;
-        }
+impl Write for TcpSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
     }
 
-    pub fn add_buffer_processor(&mut self, buffer_processor: Box<dyn BufferProcessor>) {
-        self.buffer_processors.push(buffer_processor);
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
+}
 
-    pub fn process_in(&mut self) {
-        while let Some(mut buffer) = self.unprocessed_in.pop_front() {
-            for processor in self.buffer_processors.iter_mut().rev() {
-                processor.process_buffer(&mut buffer, Direction::In);
-            }
-            self.processed_in.push_back(buffer);
+impl Transport for TcpSocket {
+    /// A writable event doesn't mean the connect succeeded: it may have failed,
+    /// or the event may be spurious while the connect is still in progress
+    fn connected(&mut self) -> io::Result<()> {
+        if let Some(err) = self.stream.take_error()? {
+            return Err(err);
         }
-    }
-    pub fn process_out(&mut self) {
-        while let Some(mut buffer) = self.unprocessed_out.pop_front() {
-            for processor in self.buffer_processors.iter_mut() {
-                processor.process_buffer(&mut buffer, Direction::Out);
+        match self.stream.peer_addr() {
+            Ok(_) => self.stream.set_nodelay(true),
+            Err(err) if err.kind() == io::ErrorKind::NotConnected => {
+                Err(io::ErrorKind::WouldBlock.into())
             }
-            self.processed_out.push_back(buffer);
+            Err(err) => Err(err),
         }
     }
+
+    fn take_error(&mut self) -> io::Result<Option<io::Error>> {
+        self.stream.take_error()
+    }
+
+    fn close(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
 }
 
 impl mio::event::Source for TcpSocket {
@@ -0,0 +1,263 @@
+use crate::net::transport::Transport;
+use mio::{Interest, Registry, Token};
+use ring::digest::{digest, SHA256};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName,
+};
+use std::convert::TryFrom;
+use std::io;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// TLS settings for the lobby connection.
+///
+/// By default the server certificate is verified against the Mozilla root certificates
+/// and the server address. Custom roots can be added (e.g. a private CA), and certificates
+/// can be pinned by the SHA-256 digest of their DER encoding. When pins are configured,
+/// the server certificate must match one of them. If no roots are trusted at all,
+/// the pin alone authenticates the server, which allows self-signed certificates.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    server_name: Option<String>,
+    default_roots: bool,
+    root_certificates: Vec<Vec<u8>>,
+    pinned_certificates: Vec<[u8; 32]>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self {
+            server_name: None,
+            default_roots: true,
+            root_certificates: Vec::new(),
+            pinned_certificates: Vec::new(),
+        }
+    }
+
+    /// Name the server certificate is verified against. Defaults to the server IP address.
+    pub fn with_server_name(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_owned());
+        self
+    }
+
+    /// Use the given server name unless one was explicitly set
+    pub(crate) fn set_default_server_name(&mut self, server_name: &str) {
+        if self.server_name.is_none() {
+            self.server_name = Some(server_name.to_owned());
+        }
+    }
+
+    /// Only trust the explicitly added root certificates
+    pub fn without_default_roots(mut self) -> Self {
+        self.default_roots = false;
+        self
+    }
+
+    /// Trust the given DER encoded root certificate
+    pub fn with_root_certificate(mut self, der: Vec<u8>) -> Self {
+        self.root_certificates.push(der);
+        self
+    }
+
+    /// Require the server certificate to have the given SHA-256 digest
+    pub fn with_pinned_certificate(mut self, sha256: [u8; 32]) -> Self {
+        self.pinned_certificates.push(sha256);
+        self
+    }
+
+    fn client_config(&self) -> io::Result<Arc<ClientConfig>> {
+        let mut root_store = RootCertStore::empty();
+        if self.default_roots {
+            root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+        }
+        for der in &self.root_certificates {
+            root_store
+                .add(&Certificate(der.clone()))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        }
+
+        let verifier = PinningVerifier {
+            webpki: if root_store.is_empty() {
+                None
+            } else {
+                Some(WebPkiVerifier::new(root_store, None))
+            },
+            pins: self.pinned_certificates.clone(),
+        };
+        if verifier.webpki.is_none() && verifier.pins.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS config trusts neither root certificates nor pinned certificates",
+            ));
+        }
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        Ok(Arc::new(config))
+    }
+}
+
+struct PinningVerifier {
+    webpki: Option<WebPkiVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if !self.pins.is_empty() {
+            let cert_digest = digest(&SHA256, &end_entity.0);
+            if !self.pins.iter().any(|pin| &pin[..] == cert_digest.as_ref()) {
+                return Err(rustls::Error::General(
+                    "Server certificate does not match any pinned certificate".to_owned(),
+                ));
+            }
+        }
+        match &self.webpki {
+            Some(webpki) => webpki.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            ),
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
+
+/// Client side TLS session running on top of another transport
+pub struct TlsTransport {
+    inner: Box<dyn Transport>,
+    conn: ClientConnection,
+}
+
+impl TlsTransport {
+    pub fn new(
+        inner: Box<dyn Transport>,
+        config: &TlsConfig,
+        addr: SocketAddr,
+    ) -> io::Result<Self> {
+        let server_name = match &config.server_name {
+            Some(name) => ServerName::try_from(name.as_str())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+            None => ServerName::IpAddress(addr.ip()),
+        };
+        let conn = ClientConnection::new(config.client_config()?, server_name)
+            .map_err(io::Error::other)?;
+        Ok(Self { inner, conn })
+    }
+}
+
+impl Read for TlsTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+
+            if self.conn.read_tls(&mut self.inner)? == 0 {
+                return Ok(0);
+            }
+            let result = self.conn.process_new_packets();
+            // Answer handshake messages, or send the alert in case of error
+            match self.flush() {
+                Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(err),
+                _ => {}
+            }
+            result.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+    }
+}
+
+impl Write for TlsTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.conn.writer().write(buf)?;
+        if n == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        // The plaintext is buffered by the session, pending records
+        // will be written on the next writable event if need be.
+        match self.flush() {
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => Err(err),
+            _ => Ok(n),
+        }
+    }
+
+    /// Write pending TLS records to the inner transport
+    fn flush(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            if self.conn.write_tls(&mut self.inner)? == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+        self.inner.flush()
+    }
+}
+
+impl Transport for TlsTransport {
+    fn connected(&mut self) -> io::Result<()> {
+        self.inner.connected()
+    }
+
+    fn take_error(&mut self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.flush();
+        self.inner.close();
+    }
+}
+
+impl mio::event::Source for TlsTransport {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
@@ -0,0 +1,389 @@
+use crate::net::packet::MAX_PACKET_SIZE;
+use crate::net::transport::Transport;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use mio::{Interest, Registry, Token};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use std::io::{Read, Write};
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_SIZE: usize = 8 * 1024;
+/// Largest frame payload accepted from the server: a packet of `MAX_PACKET_SIZE`
+/// with room for the headers and the buffer processors' overhead
+const MAX_FRAME_SIZE: usize = 2 * MAX_PACKET_SIZE;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const FIN: u8 = 0x80;
+const MASK: u8 = 0x80;
+
+/// Where to open the WebSocket, parsed from a `ws://` or `wss://` url
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    /// Value of the `Host` header
+    pub host: String,
+    pub path: String,
+}
+
+/// Client side WebSocket codec (RFC 6455). The packet stream is carried in binary frames.
+///
+/// The session doesn't do any IO itself: received bytes are fed through `decode`,
+/// and bytes to send (handshake, frames, control frames) accumulate in `out` until written.
+pub struct WebSocketSession {
+    rng: SystemRandom,
+    expected_accept: String,
+    handshake_done: bool,
+    closed: bool,
+    pending_in: Vec<u8>,
+    /// Frames sent before the handshake completed
+    queued_out: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl WebSocketSession {
+    pub fn new(config: &WebSocketConfig) -> io::Result<Self> {
+        let rng = SystemRandom::new();
+        let mut key = [0; 16];
+        rng.fill(&mut key)
+            .map_err(|_| io::Error::other("Could not generate key"))?;
+        let key = BASE64.encode(key);
+        let expected_accept = BASE64.encode(
+            digest(
+                &SHA1_FOR_LEGACY_USE_ONLY,
+                format!("{}{}", key, WEBSOCKET_GUID).as_bytes(),
+            )
+            .as_ref(),
+        );
+        let request = format!(
+            "GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            config.path, config.host, key
+        );
+        Ok(Self {
+            rng,
+            expected_accept,
+            handshake_done: false,
+            closed: false,
+            pending_in: Vec::new(),
+            queued_out: Vec::new(),
+            out: request.into_bytes(),
+        })
+    }
+
+    /// Wrap the data in a binary frame
+    pub fn encode(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write_frame(OPCODE_BINARY, data, !self.handshake_done)
+    }
+
+    /// Feed received bytes, returns the payload of the complete data frames.
+    /// Returns `ConnectionAborted` once the server closed the WebSocket.
+    pub fn decode(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.pending_in.extend_from_slice(data);
+        if !self.handshake_done && !self.read_handshake()? {
+            return Ok(Vec::new());
+        }
+
+        let mut payload = Vec::new();
+        let mut offset = 0;
+        while let Some((opcode, frame, frame_size)) = read_frame(&self.pending_in[offset..])? {
+            offset += frame_size;
+            match opcode {
+                OPCODE_BINARY | OPCODE_CONTINUATION => payload.extend_from_slice(&frame),
+                OPCODE_PING => self.write_frame(OPCODE_PONG, &frame, false)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    if !self.closed {
+                        self.close()?;
+                    }
+                    self.pending_in.clear();
+                    return if payload.is_empty() {
+                        Err(io::ErrorKind::ConnectionAborted.into())
+                    } else {
+                        Ok(payload)
+                    };
+                }
+                OPCODE_TEXT => return Err(invalid_data("Unexpected text frame")),
+                _ => return Err(invalid_data("Unknown opcode")),
+            }
+        }
+        self.pending_in.drain(..offset);
+        Ok(payload)
+    }
+
+    /// Send a close frame
+    pub fn close(&mut self) -> io::Result<()> {
+        self.closed = true;
+        self.write_frame(OPCODE_CLOSE, &[], false)
+    }
+
+    pub fn has_out(&self) -> bool {
+        !self.out.is_empty()
+    }
+
+    /// Bytes ready to be written to the underlying stream
+    pub fn out(&self) -> &[u8] {
+        &self.out
+    }
+
+    /// Mark the first `n` bytes of `out` as written
+    pub fn consume_out(&mut self, n: usize) {
+        self.out.drain(..n);
+    }
+
+    fn read_handshake(&mut self) -> io::Result<bool> {
+        let end = match self
+            .pending_in
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+        {
+            Some(position) => position + 4,
+            None if self.pending_in.len() > MAX_HANDSHAKE_SIZE => {
+                return Err(invalid_data("WebSocket handshake response too big"))
+            }
+            None => return Ok(false),
+        };
+        let response = String::from_utf8_lossy(&self.pending_in[..end]).into_owned();
+        self.pending_in.drain(..end);
+
+        let mut lines = response.split("\r\n");
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(invalid_data(&format!(
+                "WebSocket upgrade refused: {}",
+                status
+            )));
+        }
+        let accept = lines
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) => Some((name.trim(), value.trim())),
+                    _ => None,
+                }
+            })
+            .find(|(name, _)| name.eq_ignore_ascii_case("Sec-WebSocket-Accept"))
+            .map(|(_, value)| value);
+        if accept != Some(self.expected_accept.as_str()) {
+            return Err(invalid_data("Invalid Sec-WebSocket-Accept"));
+        }
+
+        self.handshake_done = true;
+        self.out.append(&mut self.queued_out);
+        Ok(true)
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8], queue: bool) -> io::Result<()> {
+        let mut mask = [0; 4];
+        self.rng
+            .fill(&mut mask)
+            .map_err(|_| io::Error::other("Could not generate mask"))?;
+        let out = if queue {
+            &mut self.queued_out
+        } else {
+            &mut self.out
+        };
+        out.push(FIN | opcode);
+        if payload.len() < 126 {
+            out.push(MASK | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            out.push(MASK | 126);
+            out.write_u16::<BigEndian>(payload.len() as u16)?;
+        } else {
+            out.push(MASK | 127);
+            out.write_u64::<BigEndian>(payload.len() as u64)?;
+        }
+        out.extend_from_slice(&mask);
+        out.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        Ok(())
+    }
+}
+
+/// WebSocket session running on top of another transport
+pub struct WebSocketTransport {
+    inner: Box<dyn Transport>,
+    session: WebSocketSession,
+    /// Payload of received frames not read yet
+    pending_in: Vec<u8>,
+    read_buffer: Vec<u8>,
+}
+
+impl WebSocketTransport {
+    pub fn new(inner: Box<dyn Transport>, config: &WebSocketConfig) -> io::Result<Self> {
+        Ok(Self {
+            inner,
+            session: WebSocketSession::new(config)?,
+            pending_in: Vec::new(),
+            read_buffer: vec![0; 4096],
+        })
+    }
+}
+
+impl Read for WebSocketTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending_in.is_empty() {
+            let n = self.inner.read(&mut self.read_buffer)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let payload = self.session.decode(&self.read_buffer[..n])?;
+            self.pending_in.extend_from_slice(&payload);
+            // Answer pings
+            match self.flush() {
+                Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(err),
+                _ => {}
+            }
+        }
+        let n = buf.len().min(self.pending_in.len());
+        buf[..n].copy_from_slice(&self.pending_in[..n]);
+        self.pending_in.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for WebSocketTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The frame is buffered, what can't be written now
+        // will be on the next writable event.
+        self.session.encode(buf)?;
+        match self.flush() {
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => Err(err),
+            _ => Ok(buf.len()),
+        }
+    }
+
+    /// Write pending frames to the inner transport
+    fn flush(&mut self) -> io::Result<()> {
+        while self.session.has_out() {
+            let n = self.inner.write(self.session.out())?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.session.consume_out(n);
+        }
+        self.inner.flush()
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn connected(&mut self) -> io::Result<()> {
+        self.inner.connected()
+    }
+
+    fn take_error(&mut self) -> io::Result<Option<io::Error>> {
+        self.inner.take_error()
+    }
+
+    fn close(&mut self) {
+        if self.session.close().is_ok() {
+            let _ = self.flush();
+        }
+        self.inner.close();
+    }
+}
+
+impl mio::event::Source for WebSocketTransport {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
+
+/// Opcode, payload and total size of the first frame of the buffer, if complete.
+/// Fails on frames larger than `MAX_FRAME_SIZE` before buffering them, and on masked frames
+/// as the server must not mask its frames (RFC 6455 §5.1).
+fn read_frame(buffer: &[u8]) -> io::Result<Option<(u8, Vec<u8>, usize)>> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let opcode = buffer[0] & 0x0F;
+    if buffer[1] & MASK != 0 {
+        return Err(invalid_data("Masked WebSocket frame from the server"));
+    }
+    let mut offset = 2;
+    let len = match buffer[1] & 0x7F {
+        126 => {
+            if buffer.len() < offset + 2 {
+                return Ok(None);
+            }
+            offset += 2;
+            BigEndian::read_u16(&buffer[2..]) as usize
+        }
+        127 => {
+            if buffer.len() < offset + 8 {
+                return Ok(None);
+            }
+            offset += 8;
+            BigEndian::read_u64(&buffer[2..]).min(usize::MAX as u64) as usize
+        }
+        len => len as usize,
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid_data("WebSocket frame too big"));
+    }
+    if buffer.len() - offset < len {
+        return Ok(None);
+    }
+    let payload = buffer[offset..offset + len].to_vec();
+    Ok(Some((opcode, payload, offset + len)))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::transport::websocket::{read_frame, OPCODE_BINARY};
+
+    #[test]
+    fn read_frame_sizes() {
+        let frame = [0x82, 3, 1, 2, 3];
+        assert_eq!(read_frame(&frame[..4]).unwrap(), None);
+        assert_eq!(
+            read_frame(&frame).unwrap(),
+            Some((OPCODE_BINARY, vec![1, 2, 3], 5))
+        );
+        // Rejected from the header, without waiting for the payload
+        assert!(read_frame(&[0x82, 127, 0, 0, 0, 1, 0, 0, 0, 0]).is_err());
+        assert!(read_frame(&[0x82, 127, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn masked_server_frame() {
+        assert!(read_frame(&[0x82, 0x83, 0, 0, 0, 0, 1, 2, 3]).is_err());
+    }
+}
@@ -0,0 +1,541 @@
+//! Specification of the wire format, as tests, to serve as a reference for other implementations.
+//!
+//! Before buffer processors (compression, encryption), a stream is a sequence of packets,
+//! each made of a header and a payload:
+//!
+//! - flags, 1 byte, see `PacketFlag`: the high bit is always set
+//! - type id, 1 byte with `ShortType`, else 2 bytes big endian
+//! - payload size, none with `FixedSize`, 1 byte with `ShortSize`, else 3 bytes big endian
+//! - payload, encoded with the `Encoding` of the packet type: by default bincode with variable
+//!   length integers, MessagePack with named fields for `#[evolvable]` packets, and bincode with
+//!   fixed width little endian integers for `#[fixed_size]` packets
+//!
+//! Payloads larger than `MAX_FRAME_SIZE` are split in frames of that size, the last one being
+//! smaller. All the frames have the header of the packet, with `Fragment` set on all but the last.
+//!
+//! The golden vectors give the header, then the payload, of every packet type.
+
+use crate::net::packet::{message_to_packet, packet_to_message, Packet, PacketFlag};
+use crate::net::packet_decoder::PacketDecoder;
+use crate::net::packet_encoder::PacketEncoder;
+use crate::net::packets;
+use crate::net::packets::*;
+use crate::net::structs::*;
+use crate::net::Message;
+use num_traits::FromPrimitive;
+use proptest::prelude::*;
+use std::cell::RefCell;
+
+thread_local! {
+    /// Packet types checked against a golden vector
+    static CHECKED: RefCell<Vec<PacketType>> = const { RefCell::new(Vec::new()) };
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    bytes.join(" ")
+}
+
+fn encode(packets: Vec<Packet>) -> Vec<u8> {
+    let mut encoder = PacketEncoder::new(8 * 1024);
+    for packet in packets {
+        encoder.add_packet(packet);
+    }
+    let mut stream = Vec::new();
+    while let Some(buffer) = encoder.next_buffer() {
+        stream.extend_from_slice(&buffer);
+    }
+    stream
+}
+
+fn header_size(flags: u8) -> usize {
+    let type_size = if flags & PacketFlag::ShortType as u8 != 0 {
+        1
+    } else {
+        2
+    };
+    let size_size = if flags & PacketFlag::FixedSize as u8 != 0 {
+        0
+    } else if flags & PacketFlag::ShortSize as u8 != 0 {
+        1
+    } else {
+        3
+    };
+    1 + type_size + size_size
+}
+
+/// Encode the message and compare with the expected bytes, then decode it back
+fn check<T>(message: &T, header: &str, payload: &str)
+where
+    T: for<'de> Message<'de>,
+{
+    let stream = encode(vec![message_to_packet(message).unwrap()]);
+    let (actual_header, actual_payload) = stream.split_at(header_size(stream[0]));
+    assert_eq!(
+        (hex(actual_header), hex(actual_payload)),
+        (header.to_owned(), payload.to_owned()),
+        "{}",
+        message.packet_info().name
+    );
+
+    let mut decoder = PacketDecoder::new();
+    decoder.push_buffer(stream.into());
+    let packet = decoder.next_packet().unwrap();
+    assert_eq!(packet.packet_type, message.packet_type());
+    let decoded: T = packet_to_message(&packet).unwrap();
+    assert_eq!(
+        decoded.serialize_data().unwrap(),
+        message.serialize_data().unwrap()
+    );
+    assert!(decoder.next_packet().is_none());
+    CHECKED.with(|checked| checked.borrow_mut().push(packet.packet_type));
+}
+
+fn profile() -> UserProfile {
+    UserProfile {
+        user_tag: "ann#1".to_owned(),
+        display_name: "Ann".to_owned(),
+        avatar_url: None,
+    }
+}
+
+fn incoming_packets() {
+    check(
+        &FatalError {
+            code: 4,
+            message: "Banned".to_owned(),
+        },
+        "e0 00 08",
+        "04 06 42 61 6e 6e 65 64",
+    );
+    check(
+        &AuthenticationResponse {
+            error_code: None,
+            session_token: Some("s".to_owned()),
+            user_profile: Some(profile()),
+        },
+        "e0 03 10",
+        "00 01 01 73 01 05 61 6e 6e 23 31 03 41 6e 6e 00",
+    );
+    check(
+        &PacketPing {
+            id: 7,
+            peer_time: 1000,
+        },
+        "c8 04",
+        "07 00 00 00 e8 03 00 00 00 00 00 00",
+    );
+    check(
+        &AddFriendRequestResponse {
+            user_tag: "bob#2".to_owned(),
+            error_code: None,
+        },
+        "e0 07 07",
+        "05 62 6f 62 23 32 00",
+    );
+    check(
+        &FriendRequestActionResponse {
+            request_id: "r1".to_owned(),
+            error_code: Some("InternalError".to_owned()),
+        },
+        "e0 09 12",
+        "02 72 31 01 0d 49 6e 74 65 72 6e 61 6c 45 72 72 6f 72",
+    );
+    check(
+        &FetchPendingFriendRequestsResponse {
+            pending_as_inviter: vec![FriendRequest {
+                id: "r1".to_owned(),
+                state: "pending".to_owned(),
+                user_profile: profile(),
+            }],
+            pending_as_invitee: vec![],
+        },
+        "e0 0b 18",
+        "01 02 72 31 07 70 65 6e 64 69 6e 67 05 61 6e 6e 23 31 03 41 6e 6e 00 00",
+    );
+    check(
+        &FetchFriendListResponse {
+            friend_list: vec![Friend {
+                user_profile: profile(),
+                is_online: true,
+            }],
+        },
+        "e0 0d 0d",
+        "01 05 61 6e 6e 23 31 03 41 6e 6e 00 01",
+    );
+    check(&RemoveFriendResponse { error_code: None }, "e0 0f 01", "00");
+    check(
+        &NewPrivateMessage {
+            profile: profile(),
+            content: "hi".to_owned(),
+            is_self: false,
+        },
+        "e0 11 0f",
+        "05 61 6e 6e 23 31 03 41 6e 6e 00 02 68 69 00",
+    );
+    check(
+        &SystemNotification {
+            content: "Maintenance".to_owned(),
+        },
+        "e0 12 15",
+        "81 a7 63 6f 6e 74 65 6e 74 ab 4d 61 69 6e 74 65 6e 61 6e 63 65",
+    );
+    check(
+        &LobbyInvite {
+            id: "i1".to_owned(),
+            inviter: profile(),
+        },
+        "e0 14 0e",
+        "02 69 31 05 61 6e 6e 23 31 03 41 6e 6e 00",
+    );
+    check(
+        &LobbyJoined {
+            lobby_id: "l1".to_owned(),
+        },
+        "e0 16 03",
+        "02 6c 31",
+    );
+    check(
+        &LobbyMemberUpdate {
+            lobby_id: "l1".to_owned(),
+            members: vec![LobbyMember {
+                user_profile: profile(),
+                role: LobbyRole::Leader,
+                is_online: true,
+            }],
+        }, "e0 17 68", "82 a8 6c 6f 62 62 79 5f 69 64 a2 6c 31 a7 6d 65 6d 62 65 72 73 91 83 ac 75 73 65 72 5f 70 72 6f 66 69 6c 65 83 a8 75 73 65 72 5f 74 61 67 a5 61 6e 6e 23 31 ac 64 69 73 70 6c 61 79 5f 6e 61 6d 65 a3 41 6e 6e aa 61 76 61 74 61 72 5f 75 72 6c c0 a4 72 6f 6c 65 a6 4c 65 61 64 65 72 a9 69 73 5f 6f 6e 6c 69 6e 65 c3");
+    check(
+        &LobbyLeft {
+            lobby_id: "l1".to_owned(),
+        },
+        "e0 18 03",
+        "02 6c 31",
+    );
+    check(
+        &NewLobbyMessage {
+            lobby_id: "l1".to_owned(),
+            profile: None,
+            content: "gg".to_owned(),
+        },
+        "e0 1a 07",
+        "02 6c 31 00 02 67 67",
+    );
+    check(
+        &DeviceCodeIssued {
+            user_code: "ABCD".to_owned(),
+            verification_uri: "https://x.io".to_owned(),
+            expires_in: 600,
+        },
+        "e0 1e 15",
+        "04 41 42 43 44 0c 68 74 74 70 73 3a 2f 2f 78 2e 69 6f fb 58 02",
+    );
+    check(
+        &UpdateProfileResponse {
+            error_code: None,
+            user_profile: Some(profile()),
+        },
+        "e0 20 0d",
+        "00 01 05 61 6e 6e 23 31 03 41 6e 6e 00",
+    );
+    check(
+        &FetchProfileResponse {
+            user_tag: "ann#1".to_owned(),
+            user_profile: None,
+        },
+        "e0 22 07",
+        "05 61 6e 6e 23 31 00",
+    );
+    check(
+        &SearchUsersResponse {
+            query: "an".to_owned(),
+            results: vec![profile()],
+        },
+        "e0 24 0f",
+        "02 61 6e 01 05 61 6e 6e 23 31 03 41 6e 6e 00",
+    );
+    check(
+        &ProfileUpdated {
+            user_profile: profile(),
+        }, "e0 25 3b", "81 ac 75 73 65 72 5f 70 72 6f 66 69 6c 65 83 a8 75 73 65 72 5f 74 61 67 a5 61 6e 6e 23 31 ac 64 69 73 70 6c 61 79 5f 6e 61 6d 65 a3 41 6e 6e aa 61 76 61 74 61 72 5f 75 72 6c c0");
+    check(
+        &Goodbye {
+            reason: "Maintenance".to_owned(),
+        },
+        "e0 28 0c",
+        "0b 4d 61 69 6e 74 65 6e 61 6e 63 65",
+    );
+    check(&GoodbyeAck {}, "e0 29 00", "");
+    check(
+        &PacketInitResponse {
+            protocol_version: 1,
+            capabilities: 3,
+        },
+        "f0 2a 02",
+        "01 03",
+    );
+    check(
+        &NewLobbyData {
+            lobby_id: "l1".to_owned(),
+            profile: profile(),
+            channel: "vote".to_owned(),
+            payload: vec![1, 2],
+        },
+        "e0 2b 16",
+        "02 6c 31 05 61 6e 6e 23 31 03 41 6e 6e 00 04 76 6f 74 65 02 01 02",
+    );
+}
+
+fn outgoing_packets() {
+    check(
+        &PacketInit {
+            min_protocol_version: 1,
+            app_version: 1,
+            max_protocol_version: 2,
+            capabilities: 3,
+        },
+        "f0 01 04",
+        "01 01 02 03",
+    );
+    check(
+        &AuthenticationRequest {
+            email: "a@b.c".to_owned(),
+            password: "pw".to_owned(),
+        },
+        "e0 02 09",
+        "05 61 40 62 2e 63 02 70 77",
+    );
+    check(
+        &PacketPong {
+            id: 7,
+            peer_time: 1000,
+        },
+        "c8 05",
+        "07 00 00 00 e8 03 00 00 00 00 00 00",
+    );
+    check(
+        &AddFriendRequest {
+            user_tag: "bob#2".to_owned(),
+        },
+        "e0 06 06",
+        "05 62 6f 62 23 32",
+    );
+    check(
+        &FriendRequestAction {
+            request_id: "r1".to_owned(),
+            action: FriendRequestActionChoice::Accept,
+        },
+        "e0 08 04",
+        "02 72 31 00",
+    );
+    check(&FetchPendingFriendRequests {}, "e0 0a 00", "");
+    check(&FetchFriendList {}, "e0 0c 00", "");
+    check(
+        &RemoveFriend {
+            user_tag: "bob#2".to_owned(),
+        },
+        "e0 0e 06",
+        "05 62 6f 62 23 32",
+    );
+    check(
+        &SendPrivateMessage {
+            user_tag: "bob#2".to_owned(),
+            content: "hi".to_owned(),
+        },
+        "e0 10 09",
+        "05 62 6f 62 23 32 02 68 69",
+    );
+    check(
+        &InviteUser {
+            user_tag: "bob#2".to_owned(),
+        },
+        "e0 13 06",
+        "05 62 6f 62 23 32",
+    );
+    check(
+        &LobbyInviteAction {
+            invite_id: "i1".to_owned(),
+            action: LobbyInviteActionChoice::Decline,
+        },
+        "e0 15 04",
+        "02 69 31 01",
+    );
+    check(
+        &SendLobbyMessage {
+            content: "gg".to_owned(),
+        },
+        "e0 19 03",
+        "02 67 67",
+    );
+    check(
+        &TokenAuthenticationRequest {
+            token: "t".to_owned(),
+        },
+        "e0 1b 02",
+        "01 74",
+    );
+    check(
+        &TicketAuthenticationRequest {
+            platform: "steam".to_owned(),
+            ticket: vec![0xca, 0xfe],
+        },
+        "e0 1c 09",
+        "05 73 74 65 61 6d 02 ca fe",
+    );
+    check(
+        &DeviceCodeAuthenticationRequest {
+            client_id: "game".to_owned(),
+        },
+        "e0 1d 05",
+        "04 67 61 6d 65",
+    );
+    check(
+        &UpdateProfile {
+            display_name: Some("Ann".to_owned()),
+            avatar_url: None,
+        },
+        "e0 1f 06",
+        "01 03 41 6e 6e 00",
+    );
+    check(
+        &FetchProfile {
+            user_tag: "ann#1".to_owned(),
+        },
+        "e0 21 06",
+        "05 61 6e 6e 23 31",
+    );
+    check(
+        &SearchUsers {
+            query: "an".to_owned(),
+        },
+        "e0 23 03",
+        "02 61 6e",
+    );
+    check(
+        &SendLobbyData {
+            channel: "vote".to_owned(),
+            payload: vec![1, 2],
+            target: None,
+        },
+        "e0 2c 09",
+        "04 76 6f 74 65 02 01 02 00",
+    );
+}
+
+#[test]
+fn golden_vectors() {
+    incoming_packets();
+    outgoing_packets();
+    let checked = CHECKED.with(|checked| checked.borrow().clone());
+    for id in 0..packets::packet_count() as u16 {
+        if let Some(packet_type) = PacketType::from_u16(id).filter(|&ty| packets::has(ty)) {
+            assert!(
+                checked.contains(&packet_type),
+                "No golden vector for {:?}",
+                packet_type
+            );
+        }
+    }
+}
+
+#[test]
+fn long_size() {
+    // 300 bytes of content, the length prefix taking 3 bytes: 0xfb then 300 in little endian
+    let message = SendLobbyMessage {
+        content: "x".repeat(300),
+    };
+    let stream = encode(vec![message_to_packet(&message).unwrap()]);
+    assert_eq!(hex(&stream[..5]), "c0 19 00 01 2f");
+    assert_eq!(hex(&stream[5..8]), "fb 2c 01");
+    assert_eq!(stream.len(), 5 + 303);
+}
+
+#[test]
+fn long_type() {
+    let stream = encode(vec![Packet::custom(0x8001, vec![1, 2])]);
+    assert_eq!(hex(&stream), "a0 80 01 02 01 02");
+}
+
+proptest! {
+    #[test]
+    fn packets_round_trip(
+        packets in prop::collection::vec(
+            (
+                prop_oneof![
+                    prop::sample::select(
+                        (0..packets::packet_count() as u16)
+                            .filter(|&id| PacketType::from_u16(id).is_some_and(packets::has))
+                            .collect::<Vec<u16>>()
+                    ),
+                    CUSTOM_PACKET_TYPES,
+                ],
+                prop::collection::vec(any::<u8>(), 0..600),
+            ),
+            1..8,
+        ),
+        chunk_size in 1usize..64,
+    ) {
+        let packets: Vec<(u16, Vec<u8>)> = packets
+            .into_iter()
+            .map(|(type_id, mut data)| {
+                if let Some(fixed_size) = PacketType::from_u16(type_id)
+                    .filter(|&ty| packets::has(ty))
+                    .and_then(|ty| packets::get(ty).fixed_size)
+                {
+                    data.resize(fixed_size, 0);
+                }
+                (type_id, data)
+            })
+            .collect();
+        let stream = encode(
+            packets
+                .iter()
+                .map(|(type_id, data)| match PacketType::from_u16(*type_id) {
+                    Some(ty) if packets::has(ty) => Packet::new(ty, data.clone()),
+                    _ => Packet::custom(*type_id, data.clone()),
+                })
+                .collect(),
+        );
+
+        let mut decoder = PacketDecoder::new();
+        let mut decoded = Vec::new();
+        for chunk in stream.chunks(chunk_size) {
+            decoder.push_buffer(chunk.to_vec().into());
+            while let Some(packet) = decoder.next_packet() {
+                decoded.push((packet.type_id, packet.data.to_vec()));
+            }
+        }
+        prop_assert_eq!(decoded, packets);
+    }
+
+    #[test]
+    fn messages_round_trip(
+        channel in ".*",
+        payload in prop::collection::vec(any::<u8>(), 0..600),
+        target in prop::option::of(".*"),
+        id in any::<u32>(),
+        peer_time in any::<u64>(),
+    ) {
+        let message = SendLobbyData {
+            channel: channel.clone(),
+            payload: payload.clone(),
+            target: target.clone(),
+        };
+        let packet = message_to_packet(&message).unwrap();
+        let decoded: SendLobbyData = packet_to_message(&packet).unwrap();
+        prop_assert_eq!(
+            (decoded.channel, decoded.payload, decoded.target),
+            (channel.clone(), payload, target)
+        );
+
+        let message = PacketPing { id, peer_time };
+        let packet = message_to_packet(&message).unwrap();
+        prop_assert_eq!(packet.data.len(), PacketPing::INFO.fixed_size.unwrap());
+        let decoded: PacketPing = packet_to_message(&packet).unwrap();
+        prop_assert_eq!((decoded.id, decoded.peer_time), (id, peer_time));
+
+        let message = SystemNotification { content: channel.clone() };
+        let packet = message_to_packet(&message).unwrap();
+        let decoded: SystemNotification = packet_to_message(&packet).unwrap();
+        prop_assert_eq!(decoded.content, channel);
+    }
+}
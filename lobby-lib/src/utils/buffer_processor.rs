@@ -1,5 +1,19 @@
+use crate::net;
+use crate::net::packet::{PacketFlag, MAX_PACKET_SIZE};
+use crate::net::ErrorKind;
 use crate::utils::byte_buffer::ByteBuffer;
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
 use log::info;
+use lz4_flex::{compress_prepend_size, decompress_size_prepended};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::agreement::{agree_ephemeral, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::digest::{digest, SHA256};
+use ring::error::Unspecified;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::SystemRandom;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Direction {
@@ -7,14 +21,32 @@ pub enum Direction {
     Out,
 }
 
+/// Transforms the byte stream of a connection.
+///
+/// Processors work stream to stream: every call consumes one buffer and pushes any number
+/// of buffers to the output, possibly none if more data is needed (e.g. to complete a frame).
+/// Returning an error closes the connection.
 pub trait BufferProcessor {
-    fn process_buffer(&mut self, buffer: &mut ByteBuffer, direction: Direction);
+    fn process(
+        &mut self,
+        buffer: ByteBuffer,
+        output: &mut VecDeque<ByteBuffer>,
+        direction: Direction,
+    ) -> net::Result<()>;
 }
 
+/// Creates a fresh processor for every new connection, since processors are stateful
+pub type BufferProcessorFactory = Rc<dyn Fn() -> Box<dyn BufferProcessor>>;
+
 pub struct LogBufferProcessor;
 
 impl BufferProcessor for LogBufferProcessor {
-    fn process_buffer(&mut self, buffer: &mut ByteBuffer, direction: Direction) {
+    fn process(
+        &mut self,
+        buffer: ByteBuffer,
+        output: &mut VecDeque<ByteBuffer>,
+        direction: Direction,
+    ) -> net::Result<()> {
         match direction {
             Direction::In => {
                 info!("Processing received buffer {:?}", &buffer[..]);
@@ -23,5 +55,717 @@ impl BufferProcessor for LogBufferProcessor {
                 info!("Processing sent buffer {:?}", &buffer[..]);
             }
         }
+        output.push_back(buffer);
+        Ok(())
+    }
+}
+
+const FRAME_HEADER_SIZE: usize = 5;
+/// Largest frame payload accepted. A buffer holds at most one packet of `MAX_PACKET_SIZE`,
+/// this leaves room for the packet headers, the LZ4 worst case expansion and the
+/// authentication tag.
+const MAX_FRAME_SIZE: usize = MAX_PACKET_SIZE + MAX_PACKET_SIZE / 255 + 1024;
+const FRAME_KEY_EXCHANGE: u8 = 1;
+const FRAME_PLAIN: u8 = 2;
+const FRAME_SEALED: u8 = 3;
+
+const CLIENT_TO_SERVER: &[u8] = b"lobby client to server";
+const SERVER_TO_CLIENT: &[u8] = b"lobby server to client";
+
+struct SessionKeys {
+    seal_key: LessSafeKey,
+    seal_counter: u64,
+    open_key: LessSafeKey,
+    open_counter: u64,
+}
+
+/// Authenticated encryption (ChaCha20-Poly1305) of the whole byte stream.
+///
+/// Both sides run the same protocol, the server using `EncryptionProcessor::server`.
+/// Every buffer is wrapped in a frame (`kind: u8`, `length: u32` big endian, payload) so that
+/// frames survive TCP re-chunking. The frames are:
+///
+/// - `1`, key exchange: the ephemeral X25519 public key of the sender, sent once
+/// - `2`, plain: data sent before the sender knew the peer's key, i.e. the `PacketInit`
+///   handshake. At most one, immediately followed by the key exchange frame of the sender.
+/// - `3`, sealed: data sealed with the key of the direction, the nonce being the number of
+///   sealed frames sent before in that direction
+///
+/// The first buffer sent goes in a plain frame followed by the key exchange frame, unless the
+/// peer's key is already known. Once both keys are known, a key per direction is derived with
+/// HKDF-SHA256 (salted with the optional pre-shared key) and everything is sealed.
+/// Buffers sent after the key exchange frame but before receiving the peer's key are held,
+/// and sealed along with the next buffer sent.
+///
+/// The key exchange travels in frames of its own rather than in the `PacketInit` handshake,
+/// which keeps the processor independent of the packets. Instead, the plain frames of both
+/// sides are mixed into the key derivation: a received plain frame is only passed on with the
+/// key following it, and if it was injected or tampered with, the first sealed frame fails.
+///
+/// Without a pre-shared key the exchange is unauthenticated: it protects against passive
+/// eavesdropping only. Use TLS to authenticate the server.
+pub struct EncryptionProcessor {
+    is_client: bool,
+    pre_shared_key: Vec<u8>,
+    private_key: Option<EphemeralPrivateKey>,
+    public_key: Vec<u8>,
+    sent_public_key: bool,
+    /// Payload of the plain frame sent, if any
+    sent_plain: Vec<u8>,
+    /// Payload of the peer's plain frame, held until its key exchange frame
+    received_plain: Option<Vec<u8>>,
+    keys: Option<SessionKeys>,
+    pending_in: Vec<u8>,
+    /// Sent while waiting for the peer's key, can't travel in plain frames anymore
+    pending_out: Vec<u8>,
+}
+
+impl Default for EncryptionProcessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EncryptionProcessor {
+    pub fn new() -> Self {
+        Self::with_role(true)
+    }
+
+    /// Processor for the server side of the exchange
+    pub fn server() -> Self {
+        Self::with_role(false)
+    }
+
+    /// Mix a secret shared with the server into the key derivation,
+    /// so that a man in the middle can't complete the exchange nor alter the plain frames.
+    pub fn with_pre_shared_key(mut self, key: &[u8]) -> Self {
+        self.pre_shared_key = key.to_vec();
+        self
+    }
+
+    fn with_role(is_client: bool) -> Self {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .expect("Could not generate key pair");
+        let public_key = private_key
+            .compute_public_key()
+            .expect("Could not compute public key")
+            .as_ref()
+            .to_vec();
+        Self {
+            is_client,
+            pre_shared_key: Vec::new(),
+            private_key: Some(private_key),
+            public_key,
+            sent_public_key: false,
+            sent_plain: Vec::new(),
+            received_plain: None,
+            keys: None,
+            pending_in: Vec::new(),
+            pending_out: Vec::new(),
+        }
+    }
+
+    fn derive_keys(
+        &mut self,
+        peer_public_key: &[u8],
+        peer_plain: &[u8],
+    ) -> Result<(), &'static str> {
+        let private_key = self
+            .private_key
+            .take()
+            .ok_or("Received more than one public key")?;
+        let sent_plain = digest(&SHA256, &self.sent_plain);
+        let peer_plain = digest(&SHA256, peer_plain);
+        let (client_key, server_key, client_plain, server_plain) = if self.is_client {
+            (
+                &self.public_key[..],
+                peer_public_key,
+                sent_plain,
+                peer_plain,
+            )
+        } else {
+            (
+                peer_public_key,
+                &self.public_key[..],
+                peer_plain,
+                sent_plain,
+            )
+        };
+        let salt = Salt::new(HKDF_SHA256, &self.pre_shared_key);
+        let (client_to_server, server_to_client) = agree_ephemeral(
+            private_key,
+            &UnparsedPublicKey::new(&X25519, peer_public_key),
+            |shared_secret| {
+                let prk = salt.extract(shared_secret);
+                let derive = |label: &[u8]| -> Result<LessSafeKey, Unspecified> {
+                    let info = [
+                        label,
+                        client_key,
+                        server_key,
+                        client_plain.as_ref(),
+                        server_plain.as_ref(),
+                    ];
+                    let okm = prk.expand(&info, &CHACHA20_POLY1305)?;
+                    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+                };
+                Ok::<_, Unspecified>((derive(CLIENT_TO_SERVER)?, derive(SERVER_TO_CLIENT)?))
+            },
+        )
+        .and_then(|keys| keys)
+        .map_err(|_| "Key agreement failed")?;
+
+        let (seal_key, open_key) = if self.is_client {
+            (client_to_server, server_to_client)
+        } else {
+            (server_to_client, client_to_server)
+        };
+        self.keys = Some(SessionKeys {
+            seal_key,
+            seal_counter: 0,
+            open_key,
+            open_counter: 0,
+        });
+        Ok(())
+    }
+
+    fn process_out(&mut self, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        let mut out = Vec::with_capacity(data.len() + 2 * FRAME_HEADER_SIZE + 64);
+        let keys = match &mut self.keys {
+            Some(keys) => keys,
+            None if !self.sent_public_key => {
+                // The key exchange frame closes the plain frames of this side
+                write_frame(&mut out, FRAME_PLAIN, data);
+                write_frame(&mut out, FRAME_KEY_EXCHANGE, &self.public_key);
+                self.sent_plain = data.to_vec();
+                self.sent_public_key = true;
+                return Ok(out);
+            }
+            None if self.pending_out.len() + data.len() > MAX_PACKET_SIZE => {
+                return Err("Peer key not received")
+            }
+            None => {
+                self.pending_out.extend_from_slice(data);
+                return Ok(out);
+            }
+        };
+        if !self.sent_public_key {
+            write_frame(&mut out, FRAME_KEY_EXCHANGE, &self.public_key);
+            self.sent_public_key = true;
+        }
+        let pending = std::mem::take(&mut self.pending_out);
+        for data in [&pending[..], data].iter().filter(|data| !data.is_empty()) {
+            let mut sealed = data.to_vec();
+            let nonce = nonce(keys.seal_counter);
+            keys.seal_counter += 1;
+            keys.seal_key
+                .seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+                .expect("Could not seal buffer");
+            write_frame(&mut out, FRAME_SEALED, &sealed);
+        }
+        Ok(out)
+    }
+
+    fn process_in(&mut self, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        self.pending_in.extend_from_slice(data);
+        let mut out = Vec::new();
+        let mut offset = 0;
+        while let Some((kind, payload)) = read_frame(&self.pending_in[offset..])? {
+            let mut payload = payload.to_vec();
+            offset += FRAME_HEADER_SIZE + payload.len();
+            match kind {
+                FRAME_KEY_EXCHANGE => {
+                    let plain = self.received_plain.take().unwrap_or_default();
+                    self.derive_keys(&payload, &plain)?;
+                    out.extend_from_slice(&plain);
+                }
+                // The peer's plain frame comes right before its key
+                FRAME_PLAIN if self.keys.is_some() || self.received_plain.is_some() => {
+                    return Err("Unexpected plain frame")
+                }
+                FRAME_PLAIN => self.received_plain = Some(payload),
+                FRAME_SEALED => {
+                    let keys = self
+                        .keys
+                        .as_mut()
+                        .ok_or("Received sealed frame before key exchange")?;
+                    let nonce = nonce(keys.open_counter);
+                    keys.open_counter += 1;
+                    let plaintext = keys
+                        .open_key
+                        .open_in_place(nonce, Aad::empty(), &mut payload)
+                        .map_err(|_| "Could not authenticate sealed frame")?;
+                    out.extend_from_slice(plaintext);
+                }
+                _ => return Err("Unknown frame kind"),
+            }
+        }
+        self.pending_in.drain(..offset);
+        Ok(out)
+    }
+}
+
+impl BufferProcessor for EncryptionProcessor {
+    fn process(
+        &mut self,
+        buffer: ByteBuffer,
+        output: &mut VecDeque<ByteBuffer>,
+        direction: Direction,
+    ) -> net::Result<()> {
+        match direction {
+            Direction::In => {
+                let data = self
+                    .process_in(&buffer[..])
+                    .map_err(|err| processing_error("Decryption failed", err))?;
+                if !data.is_empty() {
+                    output.push_back(data.into());
+                }
+            }
+            Direction::Out => {
+                if !buffer.is_empty() {
+                    let data = self
+                        .process_out(&buffer[..])
+                        .map_err(|err| processing_error("Encryption failed", err))?;
+                    output.push_back(data.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    BigEndian::write_u64(&mut nonce[4..], counter);
+    Nonce::assume_unique_for_key(nonce)
+}
+
+const FRAME_RAW: u8 = 0;
+const FRAME_LZ4: u8 = 1;
+/// Refuse to inflate frames bigger than this, to protect against decompression bombs
+const MAX_DECOMPRESSED_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct CompressionStats {
+    pub raw_bytes_in: u64,
+    pub compressed_bytes_in: u64,
+    pub raw_bytes_out: u64,
+    pub compressed_bytes_out: u64,
+}
+
+impl CompressionStats {
+    /// Compressed size over raw size of received data
+    pub fn ratio_in(&self) -> f64 {
+        ratio(self.compressed_bytes_in, self.raw_bytes_in)
+    }
+
+    /// Compressed size over raw size of sent data
+    pub fn ratio_out(&self) -> f64 {
+        ratio(self.compressed_bytes_out, self.raw_bytes_out)
+    }
+}
+
+fn ratio(compressed: u64, raw: u64) -> f64 {
+    if raw == 0 {
+        1.0
+    } else {
+        compressed as f64 / raw as f64
+    }
+}
+
+#[derive(Default)]
+struct CompressionState {
+    /// Compress what is sent
+    enabled: bool,
+    /// Whether the received stream is compressed, known from its first byte
+    compressed_in: Option<bool>,
+    stats: CompressionStats,
+}
+
+/// Shared access to a `CompressionProcessor` owned by a connection
+#[derive(Clone)]
+pub struct CompressionHandle(Rc<RefCell<CompressionState>>);
+
+impl CompressionHandle {
+    /// Start compressing what is sent. Must be called exactly at the point of the stream
+    /// agreed on with the server, i.e. right after the handshake.
+    pub fn enable(&self) {
+        self.0.borrow_mut().enabled = true;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.borrow().enabled
+    }
+
+    /// Whether the received stream is compressed, `None` until something was received
+    pub fn compressed_in(&self) -> Option<bool> {
+        self.0.borrow().compressed_in
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        self.0.borrow().stats
+    }
+}
+
+/// LZ4 compression of buffers bigger than a threshold.
+///
+/// Compression is negotiated during the handshake with `Capabilities::COMPRESSION`,
+/// so that servers which don't support it still work. Compressed streams wrap every buffer
+/// in a frame (`kind: u8`, `length: u32`, payload), compressed or not:
+///
+/// - the server, when it picks compression, frames everything it sends, starting with its
+///   `PacketInitResponse`. Since frames start with their kind and packets with
+///   `PacketFlag::FixedHeader`, the first received byte tells whether the stream is compressed.
+/// - the client frames everything it sends after its `PacketInit` once enabled, the connection
+///   holding what is sent in between until the server answered.
+pub struct CompressionProcessor {
+    threshold: usize,
+    state: Rc<RefCell<CompressionState>>,
+    pending_in: Vec<u8>,
+}
+
+impl CompressionProcessor {
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            state: Rc::new(RefCell::new(CompressionState::default())),
+            pending_in: Vec::new(),
+        }
+    }
+
+    pub fn handle(&self) -> CompressionHandle {
+        CompressionHandle(self.state.clone())
+    }
+
+    fn process_out(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + FRAME_HEADER_SIZE);
+        if data.len() > self.threshold {
+            write_frame(&mut out, FRAME_LZ4, &compress_prepend_size(data));
+        } else {
+            write_frame(&mut out, FRAME_RAW, data);
+        }
+        let mut state = self.state.borrow_mut();
+        state.stats.raw_bytes_out += data.len() as u64;
+        state.stats.compressed_bytes_out += out.len() as u64;
+        out
+    }
+
+    fn process_in(&mut self, data: &[u8]) -> Result<Vec<u8>, &'static str> {
+        self.pending_in.extend_from_slice(data);
+        let mut out = Vec::new();
+        let mut offset = 0;
+        while let Some((kind, payload)) = read_frame(&self.pending_in[offset..])? {
+            offset += FRAME_HEADER_SIZE + payload.len();
+            let raw_len = out.len();
+            match kind {
+                FRAME_RAW => out.extend_from_slice(payload),
+                FRAME_LZ4 => {
+                    if payload.len() < 4
+                        || LittleEndian::read_u32(payload) as usize > MAX_DECOMPRESSED_SIZE
+                    {
+                        return Err("Invalid compressed frame size");
+                    }
+                    let data = decompress_size_prepended(payload)
+                        .map_err(|_| "Could not decompress frame")?;
+                    out.extend_from_slice(&data);
+                }
+                _ => return Err("Unknown frame kind"),
+            }
+            let mut state = self.state.borrow_mut();
+            state.stats.raw_bytes_in += (out.len() - raw_len) as u64;
+            state.stats.compressed_bytes_in += (FRAME_HEADER_SIZE + payload.len()) as u64;
+        }
+        self.pending_in.drain(..offset);
+        Ok(out)
+    }
+}
+
+impl BufferProcessor for CompressionProcessor {
+    fn process(
+        &mut self,
+        buffer: ByteBuffer,
+        output: &mut VecDeque<ByteBuffer>,
+        direction: Direction,
+    ) -> net::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let compressed = match direction {
+            Direction::In if buffer.is_empty() => false,
+            Direction::In => *state
+                .compressed_in
+                .get_or_insert(buffer[0] & PacketFlag::FixedHeader as u8 == 0),
+            Direction::Out => state.enabled,
+        };
+        drop(state);
+        if !compressed {
+            output.push_back(buffer);
+            return Ok(());
+        }
+        match direction {
+            Direction::In => {
+                let data = self
+                    .process_in(&buffer[..])
+                    .map_err(|err| processing_error("Decompression failed", err))?;
+                if !data.is_empty() {
+                    output.push_back(data.into());
+                }
+            }
+            Direction::Out => {
+                if !buffer.is_empty() {
+                    output.push_back(self.process_out(&buffer[..]).into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn processing_error(context: &str, err: &str) -> net::Error {
+    ErrorKind::Processing(format!("{}: {}", context, err)).into()
+}
+
+fn write_frame(out: &mut Vec<u8>, kind: u8, payload: &[u8]) {
+    out.push(kind);
+    out.write_u32::<BigEndian>(payload.len() as u32).unwrap();
+    out.extend_from_slice(payload);
+}
+
+/// Kind and payload of the first frame of the buffer, if it is complete.
+/// Fails as soon as the header announces a frame larger than `MAX_FRAME_SIZE`,
+/// before buffering it.
+fn read_frame(buffer: &[u8]) -> Result<Option<(u8, &[u8])>, &'static str> {
+    if buffer.len() < FRAME_HEADER_SIZE {
+        return Ok(None);
+    }
+    let len = BigEndian::read_u32(&buffer[1..]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err("Frame too large");
+    }
+    if buffer.len() - FRAME_HEADER_SIZE < len {
+        return Ok(None);
+    }
+    Ok(Some((
+        buffer[0],
+        &buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len],
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net;
+    use crate::net::packet::MAX_PACKET_SIZE;
+    use crate::utils::buffer_processor::{
+        BufferProcessor, CompressionProcessor, Direction, EncryptionProcessor,
+    };
+    use std::collections::VecDeque;
+
+    fn try_process(
+        processor: &mut dyn BufferProcessor,
+        data: &[u8],
+        direction: Direction,
+    ) -> net::Result<Vec<u8>> {
+        let mut output = VecDeque::new();
+        processor.process(data.to_vec().into(), &mut output, direction)?;
+        Ok(output.iter().flat_map(|buffer| buffer.to_vec()).collect())
+    }
+
+    fn process(processor: &mut dyn BufferProcessor, data: &[u8], direction: Direction) -> Vec<u8> {
+        try_process(processor, data, direction).unwrap()
+    }
+
+    /// Feed the wire bytes one at a time to simulate TCP re-chunking
+    fn try_receive(processor: &mut dyn BufferProcessor, wire: &[u8]) -> net::Result<Vec<u8>> {
+        let mut received = Vec::new();
+        for byte in wire {
+            received.extend(try_process(processor, &[*byte], Direction::In)?);
+        }
+        Ok(received)
+    }
+
+    fn receive(processor: &mut dyn BufferProcessor, wire: &[u8]) -> Vec<u8> {
+        try_receive(processor, wire).unwrap()
+    }
+
+    #[test]
+    fn handshake_and_round_trip() {
+        let mut client = EncryptionProcessor::new();
+        let mut server = EncryptionProcessor::server();
+
+        // Init handshake, keys are not known yet so data travels in plain frames
+        let server_init = process(&mut server, b"server init", Direction::Out);
+        let client_init = process(&mut client, b"client init", Direction::Out);
+        assert_eq!(receive(&mut client, &server_init), b"server init");
+        assert_eq!(receive(&mut server, &client_init), b"client init");
+
+        let wire = process(&mut client, b"credentials", Direction::Out);
+        assert!(!wire.windows(11).any(|window| window == b"credentials"));
+        assert_eq!(receive(&mut server, &wire), b"credentials");
+
+        let first = process(&mut server, b"first", Direction::Out);
+        let second = process(&mut server, b"second", Direction::Out);
+        let wire = [first, second].concat();
+        assert_eq!(receive(&mut client, &wire), b"firstsecond");
+    }
+
+    #[test]
+    fn pre_shared_key_mismatch() {
+        let mut client = EncryptionProcessor::new().with_pre_shared_key(b"secret");
+        let mut server = EncryptionProcessor::server().with_pre_shared_key(b"other");
+
+        let server_init = process(&mut server, b"server init", Direction::Out);
+        let client_init = process(&mut client, b"client init", Direction::Out);
+        receive(&mut client, &server_init);
+        receive(&mut server, &client_init);
+
+        let wire = process(&mut server, b"sealed", Direction::Out);
+        assert!(try_receive(&mut client, &wire).is_err());
+    }
+
+    #[test]
+    fn tampered_frame() {
+        let mut client = EncryptionProcessor::new();
+        let mut server = EncryptionProcessor::server();
+
+        let server_init = process(&mut server, b"server init", Direction::Out);
+        let client_init = process(&mut client, b"client init", Direction::Out);
+        receive(&mut client, &server_init);
+        receive(&mut server, &client_init);
+
+        let mut wire = process(&mut server, b"sealed", Direction::Out);
+        let last = wire.len() - 1;
+        wire[last] ^= 1;
+        assert!(try_receive(&mut client, &wire).is_err());
+    }
+
+    #[test]
+    fn held_until_peer_key() {
+        let mut client = EncryptionProcessor::new();
+        let mut server = EncryptionProcessor::server();
+
+        let client_init = process(&mut client, b"client init", Direction::Out);
+        assert!(process(&mut client, b"early", Direction::Out).is_empty());
+        assert_eq!(receive(&mut server, &client_init), b"client init");
+        let server_init = process(&mut server, b"server init", Direction::Out);
+        assert_eq!(receive(&mut client, &server_init), b"server init");
+
+        let wire = process(&mut client, b" late", Direction::Out);
+        assert!(!wire.windows(5).any(|window| window == b"early"));
+        assert_eq!(receive(&mut server, &wire), b"early late");
+    }
+
+    #[test]
+    fn plain_frame_after_key_exchange() {
+        let mut client = EncryptionProcessor::new();
+        let mut server = EncryptionProcessor::server();
+
+        let client_init = process(&mut client, b"client init", Direction::Out);
+        receive(&mut server, &client_init);
+        assert!(try_receive(&mut server, &[2, 0, 0, 0, 5, b'p', b'l', b'a', b'i', b'n']).is_err());
+    }
+
+    #[test]
+    fn injected_plain_frame() {
+        let mut client = EncryptionProcessor::new().with_pre_shared_key(b"secret");
+        let mut server = EncryptionProcessor::server().with_pre_shared_key(b"secret");
+        process(&mut client, b"client init", Direction::Out);
+
+        // The server's key exchange is dropped and plain frames are injected instead
+        let injected = [2, 0, 0, 0, 5, b'f', b'o', b'r', b'g', b'e'];
+        assert!(receive(&mut client, &injected).is_empty());
+        assert!(try_receive(&mut client, &injected).is_err());
+
+        // Nor can it be slipped in before the server's own plain frame and key
+        let mut client = EncryptionProcessor::new().with_pre_shared_key(b"secret");
+        process(&mut client, b"client init", Direction::Out);
+        let server_init = process(&mut server, b"server init", Direction::Out);
+        let wire = [&injected[..], &server_init].concat();
+        assert!(try_receive(&mut client, &wire).is_err());
+    }
+
+    #[test]
+    fn tampered_plain_frame() {
+        let mut client = EncryptionProcessor::new().with_pre_shared_key(b"secret");
+        let mut server = EncryptionProcessor::server().with_pre_shared_key(b"secret");
+
+        let mut server_init = process(&mut server, b"server init", Direction::Out);
+        let client_init = process(&mut client, b"client init", Direction::Out);
+        server_init[5] = b'S';
+        assert_eq!(receive(&mut client, &server_init), b"Server init");
+        receive(&mut server, &client_init);
+
+        // Detected with the first sealed frame
+        let wire = process(&mut server, b"sealed", Direction::Out);
+        assert!(try_receive(&mut client, &wire).is_err());
+    }
+
+    #[test]
+    fn pending_out_bounded() {
+        let mut client = EncryptionProcessor::new();
+        process(&mut client, b"client init", Direction::Out);
+        let data = vec![0; MAX_PACKET_SIZE / 2 + 1];
+        process(&mut client, &data, Direction::Out);
+        assert!(try_process(&mut client, &data, Direction::Out).is_err());
+    }
+
+    #[test]
+    fn frame_too_large() {
+        // Rejected from the header, without waiting for the payload
+        let mut processor = EncryptionProcessor::server();
+        assert!(try_process(&mut processor, &[2, 0xff, 0xff, 0xff, 0xff], Direction::In).is_err());
+    }
+
+    #[test]
+    fn compression_disabled_until_negotiated() {
+        let mut processor = CompressionProcessor::new(16);
+        let data = vec![1; 256];
+        assert_eq!(process(&mut processor, &data, Direction::Out), data);
+        processor.handle().enable();
+        assert_ne!(process(&mut processor, &data, Direction::Out), data);
+
+        // The peer didn't pick compression: its stream starts with a packet
+        let mut processor = CompressionProcessor::new(16);
+        let data = vec![0x80; 256];
+        assert_eq!(process(&mut processor, &data, Direction::In), data);
+        assert_eq!(process(&mut processor, &[0, 1], Direction::In), [0, 1]);
+        assert_eq!(processor.handle().compressed_in(), Some(false));
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        let mut client = CompressionProcessor::new(64);
+        let mut server = CompressionProcessor::new(64);
+        client.handle().enable();
+
+        let small = vec![1; 64];
+        let big = vec![2; 4096];
+        let wire = [
+            process(&mut client, &small, Direction::Out),
+            process(&mut client, &big, Direction::Out),
+        ]
+        .concat();
+        assert!(wire.len() < small.len() + big.len());
+        assert_eq!(receive(&mut server, &wire), [small, big].concat());
+
+        let client_stats = client.handle().stats();
+        let server_stats = server.handle().stats();
+        assert_eq!(server.handle().compressed_in(), Some(true));
+        assert_eq!(client_stats.raw_bytes_out, 64 + 4096);
+        assert_eq!(client_stats.compressed_bytes_out, wire.len() as u64);
+        assert_eq!(server_stats.raw_bytes_in, 64 + 4096);
+        assert_eq!(server_stats.compressed_bytes_in, wire.len() as u64);
+        assert!(server_stats.ratio_in() < 0.1);
+    }
+
+    #[test]
+    fn compression_bomb() {
+        let mut processor = CompressionProcessor::new(64);
+        let mut wire = vec![1, 0, 0, 0, 8];
+        wire.extend_from_slice(&u32::MAX.to_le_bytes());
+        wire.extend_from_slice(&[0; 4]);
+        assert!(try_process(&mut processor, &wire, Direction::In).is_err());
+    }
+
+    #[test]
+    fn compression_frame_too_large() {
+        let mut processor = CompressionProcessor::new(64);
+        assert!(try_process(&mut processor, &[0, 0xff, 0xff, 0xff, 0xff], Direction::In).is_err());
     }
 }
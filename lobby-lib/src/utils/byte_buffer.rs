@@ -1,6 +1,8 @@
 use crate::net::Message;
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use std::borrow::Borrow;
+use std::io;
+use std::mem::MaybeUninit;
 use std::ops::Deref;
 
 pub struct ByteBuffer(Bytes);
@@ -13,6 +15,12 @@ impl ByteBuffer {
     }
 }
 
+impl From<ByteBuffer> for Bytes {
+    fn from(buffer: ByteBuffer) -> Self {
+        buffer.0
+    }
+}
+
 impl From<Bytes> for ByteBuffer {
     fn from(bytes: Bytes) -> Self {
         ByteBuffer(bytes)
@@ -47,14 +55,129 @@ impl Borrow<[u8]> for ByteBuffer {
     }
 }
 
-#[cfg(tests)]
+/// Buffer read into without going through an intermediate buffer, the data being split off
+/// as it is consumed.
+///
+/// Reads must be given initialized memory, so the spare capacity is zeroed, but only when
+/// the buffer grows: like `std::io::BorrowedBuf`, the bytes known to be initialized past
+/// the data are tracked, and reused by the next reads.
+#[derive(Default)]
+pub struct ReadBuffer {
+    buffer: BytesMut,
+    /// Initialized bytes of the spare capacity, from its start
+    initialized: usize,
+}
+
+impl ReadBuffer {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            buffer: BytesMut::with_capacity(capacity),
+            initialized: 0,
+        }
+    }
+
+    /// Read up to `size` bytes at the end of the buffer. Returns the number of bytes read.
+    pub fn read_from<F>(&mut self, size: usize, read: F) -> io::Result<usize>
+    where
+        F: FnOnce(&mut [u8]) -> io::Result<usize>,
+    {
+        self.reserve(size);
+        let spare = &mut self.buffer.bytes_mut()[..size];
+        for byte in &mut spare[self.initialized.min(size)..] {
+            *byte = MaybeUninit::new(0);
+        }
+        self.initialized = self.initialized.max(size);
+        // SAFETY: the `size` first bytes of the spare capacity are initialized
+        let n = read(unsafe { &mut *(spare as *mut [MaybeUninit<u8>] as *mut [u8]) })?;
+        assert!(n <= size, "Read {} bytes into {}", n, size);
+        // SAFETY: the bytes read are initialized
+        unsafe { self.buffer.advance_mut(n) };
+        self.initialized -= n;
+        Ok(n)
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.reserve(data.len());
+        self.buffer.extend_from_slice(data);
+        self.initialized = self.initialized.saturating_sub(data.len());
+    }
+
+    /// Split off the first `at` bytes of data, which leaves the spare capacity as is
+    pub fn split_to(&mut self, at: usize) -> BytesMut {
+        self.buffer.split_to(at)
+    }
+
+    /// Reserve room for `additional` bytes, keeping track of the initialized spare capacity
+    fn reserve(&mut self, additional: usize) {
+        let end = self.buffer.as_ptr() as usize + self.buffer.capacity();
+        let initialized_end = self.data_end() + self.initialized;
+        let allocated = self.buffer.capacity() > 0;
+        // Reuses the memory of the bytes split off the buffer if they were all dropped,
+        // by moving the data to the start of the allocation: its end doesn't move.
+        // Otherwise the data is copied to a new allocation, while the current one is still
+        // alive, so that the end does move.
+        self.buffer.reserve(additional);
+        let new_end = self.buffer.as_ptr() as usize + self.buffer.capacity();
+        self.initialized = if allocated && new_end == end {
+            // The bytes before the data were data before
+            initialized_end - self.data_end()
+        } else {
+            0
+        };
+    }
+
+    fn data_end(&self) -> usize {
+        self.buffer.as_ptr() as usize + self.buffer.len()
+    }
+}
+
+impl Deref for ReadBuffer {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        self.buffer.deref()
+    }
+}
+
+#[cfg(test)]
 mod tests {
-    use crate::utils::byte_buffer::ByteBuffer;
+    use crate::utils::byte_buffer::{ByteBuffer, ReadBuffer};
     use bytes::Bytes;
+    use std::io;
+
+    #[test]
+    fn read_buffer() {
+        let mut buffer = ReadBuffer::with_capacity(16);
+        let n = buffer
+            .read_from(8, |buf| {
+                assert_eq!(buf, &[0; 8]);
+                buf[..3].copy_from_slice(&[1, 2, 3]);
+                Ok(3)
+            })
+            .unwrap();
+        assert_eq!(n, 3);
+        assert_eq!(&buffer[..], &[1, 2, 3]);
+        assert_eq!(buffer.initialized, 5);
+
+        // Failed reads keep nothing
+        let err = buffer.read_from(8, |_| Err(io::ErrorKind::WouldBlock.into()));
+        assert!(err.is_err());
+        assert_eq!(&buffer[..], &[1, 2, 3]);
+
+        // The memory of the data split off and dropped is reused, still initialized
+        let data = buffer.split_to(3);
+        assert_eq!(&data[..], &[1, 2, 3]);
+        drop(data);
+        buffer.extend_from_slice(&[4; 10]);
+        buffer.read_from(16, |buf| Ok(buf.len())).unwrap();
+        assert_eq!(buffer.len(), 26);
+        assert_eq!(buffer.initialized, 0);
+    }
 
     #[test]
     fn skip() {
-        let mut buffer = ByteBuffer(Bytes::from(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]));
+        let mut buffer = ByteBuffer(Bytes::from_static(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]));
         buffer.skip(4);
 
         assert_eq!(&buffer[..], &[5, 6, 7, 8, 9, 10])